@@ -0,0 +1,196 @@
+//! Subcommands for inspecting and editing the database while the server is
+//! stopped.
+
+use crate::{db::*, get_current_itelex_timestamp, ClientType, UnboxedEntry, CONFIG};
+use anyhow::Context;
+use std::{
+    fs::File,
+    io::{BufWriter, Read},
+};
+
+const USAGE: &str = "\
+usage: rust_i_telex_teilnehmerserver [<command> [<arguments>]]
+
+Without a command the server is started. The commands operate on the database
+directly and must only be used while the server is stopped. Commands that
+change the database need a SERVER_PIN:
+
+    dump               print all entries as JSON, one per line
+    get <number>       print the entry with <number> as JSON
+    set [<json>]       create or overwrite an entry with <json> or stdin
+    delete <number>    mark the entry with <number> as deleted
+    import <file>      merge the entries of a database file, keeping newer ones
+    import-legacy <file>
+                       merge the entries of a database of the Node.js server
+    export <file>      write all entries to a database file
+    snapshots          list the database snapshots
+    restore <id>       restore the database snapshot with <id>
+    help               print this message
+";
+
+/// The commands that change the database
+const MUTATING_COMMANDS: &[&str] = &["set", "delete", "import", "import-legacy", "restore"];
+
+fn argument<'a>(args: &'a [String], index: usize, name: &str) -> anyhow::Result<&'a str> {
+    args.get(index)
+        .map(String::as_str)
+        .ok_or_else(|| anyhow!("missing argument <{}>\n\n{}", name, USAGE))
+}
+
+fn parse_number(input: &str) -> anyhow::Result<u32> {
+    input.parse().with_context(|| format!("invalid number: `{}`", input))
+}
+
+/// Run the subcommand in `args`, returning `false` if there is none and the
+/// server should be started
+pub async fn run(args: &[String]) -> anyhow::Result<bool> {
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => return Ok(false),
+    };
+
+    if config!(SERVER_PIN) == 0 && MUTATING_COMMANDS.contains(&command) {
+        // NOTE: without a server pin, nothing is written to disk
+        bail!(anyhow!("`{}` can't change the database without a SERVER_PIN", command));
+    }
+
+    let changed_db = match command {
+        "dump" => {
+            for entry in get_all_entries()? {
+                println!("{}", serde_json::to_string(&entry)?);
+            }
+
+            false
+        }
+        "get" => {
+            let number = parse_number(argument(args, 1, "number")?)?;
+
+            let entry = get_entry_by_number(number)?
+                .ok_or_else(|| anyhow!("there is no entry with number {}", number))?;
+
+            println!("{}", serde_json::to_string_pretty(&entry)?);
+
+            false
+        }
+        "set" => {
+            let json = if let Some(json) = args.get(1) {
+                json.clone()
+            } else {
+                let mut json = String::new();
+                std::io::stdin().read_to_string(&mut json)?;
+                json
+            };
+
+            let mut entry: UnboxedEntry =
+                serde_json::from_str(&json).context("Failed to deserialize entry")?;
+
+            validate_entry(&entry)?;
+
+            entry.timestamp = get_current_itelex_timestamp();
+
+            update_entry(Box::new(entry), ChangeSource::Cli)?;
+
+            true
+        }
+        "delete" => {
+            let number = parse_number(argument(args, 1, "number")?)?;
+
+            let mut entry = get_entry_by_number(number)?
+                .ok_or_else(|| anyhow!("there is no entry with number {}", number))?;
+
+            entry.client_type = ClientType::Deleted;
+            entry.timestamp = get_current_itelex_timestamp();
+
+            update_entry(entry, ChangeSource::Cli)?;
+
+            true
+        }
+        "import" => {
+            let path = argument(args, 1, "file")?;
+
+            let buffer = std::fs::read(path).with_context(|| format!("Failed to read {}", path))?;
+
+            let entries = format::read_entries(&buffer, config!(DB_RECOVERY_MODE))?;
+
+            let n_entries = entries.len();
+            let mut n_imported = 0;
+
+            for entry in entries {
+                if update_entry_if_newer(Box::new(entry), ChangeSource::Cli)? {
+                    n_imported += 1;
+                }
+            }
+
+            println!("imported {} of {} entries", n_imported, n_entries);
+
+            true
+        }
+        "import-legacy" => {
+            let path = argument(args, 1, "file")?;
+
+            let report = legacy::import_legacy_database(path)?;
+
+            for error in &report.errors {
+                eprintln!("row {}: {}", error.row, error.error);
+            }
+
+            println!(
+                "imported {} of {} entries, {} could not be converted",
+                report.n_imported,
+                report.n_rows,
+                report.errors.len()
+            );
+
+            report.n_imported > 0
+        }
+        "export" => {
+            let path = argument(args, 1, "file")?;
+
+            let file = File::create(path).with_context(|| format!("Failed to create {}", path))?;
+
+            let mut writer = format::DbWriter::new(BufWriter::new(file))?;
+
+            let entries = get_all_entries()?;
+
+            for entry in &entries {
+                writer.write(entry)?;
+            }
+
+            writer.finish()?.into_inner()?.sync_all()?;
+
+            println!("exported {} entries", entries.len());
+
+            false
+        }
+        "snapshots" => {
+            for snapshot in snapshots::list_snapshots()? {
+                println!("{}\t{} bytes", snapshot.id, snapshot.size);
+            }
+
+            false
+        }
+        "restore" => {
+            let id = argument(args, 1, "id")?;
+            let id = id.parse().with_context(|| format!("invalid snapshot id: `{}`", id))?;
+
+            let n_restored = snapshots::restore_snapshot(id)?;
+
+            println!("restored {} entries", n_restored);
+
+            true
+        }
+        "help" | "--help" | "-h" => {
+            print!("{}", USAGE);
+
+            false
+        }
+
+        _ => bail!(anyhow!("unknown command: `{}`\n\n{}", command, USAGE)),
+    };
+
+    if changed_db {
+        sync_db_to_disk().await?;
+    }
+
+    Ok(true)
+}
@@ -1,3 +1,4 @@
+use crate::db::{conflicts::ConflictPolicy, StorageBackend};
 use anyhow::Context;
 use std::{net::SocketAddr, time::Duration};
 
@@ -5,14 +6,36 @@ use std::{net::SocketAddr, time::Duration};
 pub struct Config {
     pub CLIENT_TIMEOUT: Duration,
     pub SERVER_COOLDOWN: Duration,
+    pub SERVER_COOLDOWN_MAX: Duration,
+    pub SERVER_CIRCUIT_BREAKER_THRESHOLD: u32,
+    pub SERVER_GIVE_UP_AFTER: Duration,
     pub CHANGED_SYNC_INTERVAL: Duration,
     pub DB_SYNC_INTERVAL: Duration,
     pub FULL_QUERY_INTERVAL: Duration,
+    pub FULL_QUERY_MAX_AGE: Duration,
+    pub SYNC_CONFLICT_POLICY: ConflictPolicy,
+    pub SYNC_MAX_CLOCK_SKEW: Option<Duration>,
+    pub SYNC_QUARANTINE_SKEW: Duration,
     pub SERVER_PORT: u16,
     pub SERVER_PIN: u32,
+    pub DB_BACKEND: StorageBackend,
     pub DB_PATH: String,
+    pub DB_SQLITE_PATH: String,
     pub DB_PATH_TEMP: String,
-    pub SERVERS: Vec<SocketAddr>,
+    pub DB_JOURNAL_PATH: String,
+    pub DB_CHANGED_PATH: String,
+    pub DB_OUTBOX_PATH: String,
+    pub DB_REPLICATION_PATH: String,
+    pub DB_CONFLICTS_PATH: String,
+    pub DB_QUARANTINE_PATH: String,
+    pub DB_PEERS_PATH: String,
+    pub DB_RECOVERY_MODE: bool,
+    pub SEARCH_RANKING: bool,
+    pub DB_HISTORY_PATH: String,
+    pub DB_SNAPSHOT_DIR: String,
+    pub DB_SNAPSHOT_COUNT: usize,
+    pub DB_SNAPSHOT_MAX_AGE: Duration,
+    pub SERVERS: Vec<String>,
     pub LOG_FILE_PATH: Option<String>,
     pub LOG_LEVEL_FILE: Option<String>,
     pub LOG_LEVEL_TERM: Option<String>,
@@ -26,23 +49,42 @@ pub struct Config {
 
 impl std::fmt::Debug for Config {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let servers: Vec<String> =
-            self.SERVERS.iter().map(|server| format!("{}", server)).collect();
-
         #[derive(Debug)]
         struct Censored;
 
         f.debug_struct("Config")
             .field("client timeout", &self.CLIENT_TIMEOUT)
             .field("server cooldown", &self.SERVER_COOLDOWN)
+            .field("server cooldown max", &self.SERVER_COOLDOWN_MAX)
+            .field("server circuit breaker threshold", &self.SERVER_CIRCUIT_BREAKER_THRESHOLD)
+            .field("server give up after", &self.SERVER_GIVE_UP_AFTER)
             .field("changed sync interval", &self.CHANGED_SYNC_INTERVAL)
             .field("db sync interval", &self.DB_SYNC_INTERVAL)
             .field("full query interval", &self.FULL_QUERY_INTERVAL)
+            .field("full query max age", &self.FULL_QUERY_MAX_AGE)
+            .field("sync conflict policy", &self.SYNC_CONFLICT_POLICY)
+            .field("sync max clock skew", &self.SYNC_MAX_CLOCK_SKEW)
+            .field("sync quarantine skew", &self.SYNC_QUARANTINE_SKEW)
             .field("server port", &self.SERVER_PORT)
             .field("server pin", &self.SERVER_PIN)
+            .field("db backend", &self.DB_BACKEND)
             .field("db path", &self.DB_PATH)
+            .field("db sqlite path", &self.DB_SQLITE_PATH)
             .field("db path temp", &self.DB_PATH_TEMP)
-            .field("servers", &servers)
+            .field("db journal path", &self.DB_JOURNAL_PATH)
+            .field("db changed path", &self.DB_CHANGED_PATH)
+            .field("db outbox path", &self.DB_OUTBOX_PATH)
+            .field("db replication path", &self.DB_REPLICATION_PATH)
+            .field("db conflicts path", &self.DB_CONFLICTS_PATH)
+            .field("db quarantine path", &self.DB_QUARANTINE_PATH)
+            .field("db peers path", &self.DB_PEERS_PATH)
+            .field("db recovery mode", &self.DB_RECOVERY_MODE)
+            .field("search ranking", &self.SEARCH_RANKING)
+            .field("db history path", &self.DB_HISTORY_PATH)
+            .field("db snapshot dir", &self.DB_SNAPSHOT_DIR)
+            .field("db snapshot count", &self.DB_SNAPSHOT_COUNT)
+            .field("db snapshot max age", &self.DB_SNAPSHOT_MAX_AGE)
+            .field("servers", &self.SERVERS)
             .field("log file path", &self.LOG_FILE_PATH)
             .field("log level file", &self.LOG_LEVEL_FILE)
             .field("log level term", &self.LOG_LEVEL_TERM)
@@ -61,6 +103,13 @@ macro_rules! get_variable {
     };
 }
 
+/// A path that defaults to `DB_PATH` followed by `.<suffix>`
+macro_rules! get_db_path {
+    ($name:literal, $db_path:expr, $suffix:literal) => {
+        var($name).unwrap_or_else(|_| format!("{}.{}", $db_path, $suffix))
+    };
+}
+
 macro_rules! parse_duration {
     ($name:literal) => {
         duration_from_string(get_variable!($name))
@@ -86,16 +135,76 @@ macro_rules! parse_bytes_from_base64_str {
 impl Config {
     pub async fn from_env() -> anyhow::Result<Self> {
         use std::env::var;
+
+        let db_path: String = get_variable!("DB_PATH");
+
         Ok(Self {
             CLIENT_TIMEOUT: parse_duration!("CLIENT_TIMEOUT"),
             SERVER_COOLDOWN: parse_duration!("SERVER_COOLDOWN"),
+            SERVER_COOLDOWN_MAX: var("SERVER_COOLDOWN_MAX")
+                .map_or(Ok(Duration::from_secs(60 * 60)), duration_from_string)
+                .context("Failed to parse config variable SERVER_COOLDOWN_MAX as duration")?,
+            SERVER_CIRCUIT_BREAKER_THRESHOLD: var("SERVER_CIRCUIT_BREAKER_THRESHOLD")
+                .ok()
+                .map(|threshold| threshold.parse())
+                .transpose()
+                .context("Failed to parse config variable SERVER_CIRCUIT_BREAKER_THRESHOLD")?
+                .unwrap_or(10),
+            SERVER_GIVE_UP_AFTER: var("SERVER_GIVE_UP_AFTER")
+                .map_or(Ok(Duration::from_secs(60 * 60 * 24)), duration_from_string)
+                .context("Failed to parse config variable SERVER_GIVE_UP_AFTER as duration")?,
             CHANGED_SYNC_INTERVAL: parse_duration!("CHANGED_SYNC_INTERVAL"),
             DB_SYNC_INTERVAL: parse_duration!("DB_SYNC_INTERVAL"),
             FULL_QUERY_INTERVAL: parse_duration!("FULL_QUERY_INTERVAL"),
+            FULL_QUERY_MAX_AGE: var("FULL_QUERY_MAX_AGE")
+                .map_or(Ok(Duration::from_secs(60 * 60 * 24 * 7)), duration_from_string)
+                .context("Failed to parse config variable FULL_QUERY_MAX_AGE as duration")?,
+            SYNC_CONFLICT_POLICY: var("SYNC_CONFLICT_POLICY")
+                .ok()
+                .map(|policy| policy.parse())
+                .transpose()
+                .context("Failed to parse config variable SYNC_CONFLICT_POLICY")?
+                .unwrap_or(ConflictPolicy::LastWriterWins),
+            SYNC_MAX_CLOCK_SKEW: var("SYNC_MAX_CLOCK_SKEW")
+                .ok()
+                .map(duration_from_string)
+                .transpose()
+                .context("Failed to parse config variable SYNC_MAX_CLOCK_SKEW as duration")?,
+            SYNC_QUARANTINE_SKEW: var("SYNC_QUARANTINE_SKEW")
+                .map_or(Ok(Duration::from_secs(60 * 60 * 24)), duration_from_string)
+                .context("Failed to parse config variable SYNC_QUARANTINE_SKEW as duration")?,
             SERVER_PORT: parse_from_str!("SERVER_PORT"),
             SERVER_PIN: parse_from_str!("SERVER_PIN"),
-            DB_PATH: get_variable!("DB_PATH"),
+            DB_BACKEND: var("DB_BACKEND")
+                .ok()
+                .map(|backend| backend.parse())
+                .transpose()
+                .context("Failed to parse config variable DB_BACKEND")?
+                .unwrap_or(StorageBackend::File),
+            DB_SQLITE_PATH: get_db_path!("DB_SQLITE_PATH", db_path, "sqlite"),
+            DB_PATH: db_path.clone(),
             DB_PATH_TEMP: get_variable!("DB_PATH_TEMP"),
+            DB_JOURNAL_PATH: get_db_path!("DB_JOURNAL_PATH", db_path, "journal"),
+            DB_CHANGED_PATH: get_db_path!("DB_CHANGED_PATH", db_path, "changed"),
+            DB_OUTBOX_PATH: get_db_path!("DB_OUTBOX_PATH", db_path, "outbox.json"),
+            DB_REPLICATION_PATH: get_db_path!("DB_REPLICATION_PATH", db_path, "replication.json"),
+            DB_CONFLICTS_PATH: get_db_path!("DB_CONFLICTS_PATH", db_path, "conflicts.json"),
+            DB_QUARANTINE_PATH: get_db_path!("DB_QUARANTINE_PATH", db_path, "quarantine.json"),
+            DB_PEERS_PATH: get_db_path!("DB_PEERS_PATH", db_path, "peers.json"),
+            DB_RECOVERY_MODE: var("DB_RECOVERY_MODE")
+                .map_or(Ok(false), |recovery_mode| recovery_mode.parse())
+                .context("Failed to parse config variable DB_RECOVERY_MODE")?,
+            SEARCH_RANKING: var("SEARCH_RANKING")
+                .map_or(Ok(true), |ranking| ranking.parse())
+                .context("Failed to parse config variable SEARCH_RANKING")?,
+            DB_HISTORY_PATH: get_db_path!("DB_HISTORY_PATH", db_path, "history.jsonl"),
+            DB_SNAPSHOT_DIR: get_db_path!("DB_SNAPSHOT_DIR", db_path, "snapshots"),
+            DB_SNAPSHOT_COUNT: var("DB_SNAPSHOT_COUNT")
+                .map_or(Ok(48), |count| count.parse())
+                .context("Failed to parse config variable DB_SNAPSHOT_COUNT as number")?,
+            DB_SNAPSHOT_MAX_AGE: var("DB_SNAPSHOT_MAX_AGE")
+                .map_or(Ok(Duration::from_secs(60 * 60 * 24 * 7 * 2)), duration_from_string)
+                .context("Failed to parse config variable DB_SNAPSHOT_MAX_AGE as duration")?,
             LOG_FILE_PATH: var("LOG_FILE_PATH").ok(),
             LOG_LEVEL_FILE: var("LOG_LEVEL_FILE").ok(),
             LOG_LEVEL_TERM: var("LOG_LEVEL_TERM").ok(),
@@ -106,40 +215,36 @@ impl Config {
             WEBSERVER_REMOVE_SESSIONS_INTERVAL: parse_duration!(
                 "WEBSERVER_REMOVE_SESSIONS_INTERVAL"
             ),
-            SERVERS: parse_servers(get_variable!("SERVERS"))
-                .await
-                .context("failed to parse servers")?,
+            SERVERS: parse_servers(get_variable!("SERVERS")),
         })
     }
 }
 
-async fn parse_servers(input: String) -> anyhow::Result<Vec<SocketAddr>> {
-    let mut servers: Vec<SocketAddr> = Vec::new();
-
-    for entry in input.split(',') {
-        if entry == "" {
-            continue;
-        }
-
-        // use tokio::net::ToSocketAddrs;
-        // let socket_addrs = entry.trim().to_socket_addrs().await?;
+/// The servers are only resolved when we connect to them, so that we notice
+/// when their addresses change
+fn parse_servers(input: String) -> Vec<String> {
+    input
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(String::from)
+        .collect()
+}
 
-        use tokio::net::lookup_host;
-        let mut socket_addrs: Vec<SocketAddr> = lookup_host(entry.trim()).await?.collect();
+/// Look up the address of the server at `host` (`<hostname>:<port>`)
+pub async fn resolve_server(host: &str) -> anyhow::Result<Option<SocketAddr>> {
+    // use tokio::net::ToSocketAddrs;
+    // let socket_addrs = entry.trim().to_socket_addrs().await?;
 
-        // only use the first result to prevent syncing a server twice
-        // (e.g. if there is both an Ipv4 and an Ipv6 address for a server)
-        // We prefer ipv4 addresses, since older servers only listen on those
-        let ipv4 = socket_addrs.iter().find(|addr| addr.is_ipv4());
+    use tokio::net::lookup_host;
+    let socket_addrs: Vec<SocketAddr> = lookup_host(host).await?.collect();
 
-        if let Some(addr) = ipv4 {
-            servers.push(*addr);
-        } else if !socket_addrs.is_empty() {
-            servers.push(socket_addrs.remove(0));
-        }
-    }
+    // only use the first result to prevent syncing a server twice
+    // (e.g. if there is both an Ipv4 and an Ipv6 address for a server)
+    // We prefer ipv4 addresses, since older servers only listen on those
+    let ipv4 = socket_addrs.iter().find(|addr| addr.is_ipv4());
 
-    Ok(servers)
+    Ok(ipv4.or_else(|| socket_addrs.first()).copied())
 }
 
 fn duration_from_string(input: String) -> anyhow::Result<Duration> {
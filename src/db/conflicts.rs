@@ -0,0 +1,325 @@
+//! How entries received from peers are merged with our own, and a log of the
+//! conflicts an admin has to resolve, stored in `DB_CONFLICTS_PATH`.
+
+use super::{
+    get_entry_by_number, history, quarantine, replication, update_entry, write_json_file,
+    ChangeSource,
+};
+use crate::{get_current_itelex_timestamp, UnboxedEntry, CONFIG};
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use std::{
+    fs,
+    net::SocketAddr,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// The entry with the newer timestamp wins
+    LastWriterWins,
+    /// Entries that were last changed on this server are never overwritten
+    /// by peers
+    PreferLocal,
+    /// Entries from the peer at this host always win
+    PreferPeer(String),
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> anyhow::Result<Self> {
+        match input {
+            "last-writer-wins" => Ok(Self::LastWriterWins),
+            "prefer-local" => Ok(Self::PreferLocal),
+            _ => {
+                if let Some(peer) = input.strip_prefix("prefer-peer:") {
+                    if peer.is_empty() {
+                        bail!(anyhow!("invalid peer: `{}`", peer));
+                    }
+
+                    Ok(Self::PreferPeer(peer.to_owned()))
+                } else {
+                    Err(anyhow!("unknown conflict policy: `{}`", input))
+                }
+            }
+        }
+    }
+}
+
+/// What to do with an entry received from a peer
+#[derive(Debug)]
+pub enum Resolution {
+    Accept,
+    /// The entry is outdated
+    Ignore,
+    /// The entry has to be resolved by an admin, for the contained reason
+    Conflict(String),
+    /// The entry's timestamp is too far in the future to be compared
+    Quarantine,
+}
+
+/// Which version of an entry wins a conflict
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Winner {
+    Local,
+    Remote,
+}
+
+impl std::str::FromStr for Winner {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> anyhow::Result<Self> {
+        match input {
+            "local" => Ok(Self::Local),
+            "remote" => Ok(Self::Remote),
+
+            _ => Err(anyhow!("unknown winner: `{}`", input)),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ConflictRecord {
+    pub id: u64,
+    /// The itelex timestamp of when the conflict was detected
+    pub time: u32,
+    pub peer: SocketAddr,
+    pub reason: String,
+    /// Our version of the entry when the conflict was detected
+    pub local: Option<UnboxedEntry>,
+    /// The version of the entry the peer sent us
+    pub remote: UnboxedEntry,
+    pub resolved: bool,
+}
+
+/// How many resolved conflicts are kept in the log
+const MAX_RESOLVED_CONFLICTS: usize = 1000;
+
+static CONFLICTS: Lazy<Mutex<Vec<ConflictRecord>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+fn lock_conflicts() -> MutexGuard<'static, Vec<ConflictRecord>> {
+    CONFLICTS.lock().expect("the conflict log lock was poisoned")
+}
+
+pub fn load_conflicts() -> anyhow::Result<()> {
+    let conflicts_path = Path::new(&config!(DB_CONFLICTS_PATH));
+
+    if !conflicts_path.exists() {
+        return Ok(());
+    }
+
+    let conflicts: Vec<ConflictRecord> = serde_json::from_slice(&fs::read(conflicts_path)?)
+        .context("Failed to read the conflict log")?;
+
+    let n_unresolved = conflicts.iter().filter(|conflict| !conflict.resolved).count();
+
+    if n_unresolved > 0 {
+        warn!("There are {} unresolved conflicts", n_unresolved);
+    }
+
+    *lock_conflicts() = conflicts;
+
+    Ok(())
+}
+
+fn write_conflicts(conflicts: &[ConflictRecord]) -> anyhow::Result<()> {
+    write_json_file(&config!(DB_CONFLICTS_PATH), &conflicts)
+        .context("Failed to write the conflict log")
+}
+
+/// Whether the entries only differ in their timestamps
+fn same_content(a: &UnboxedEntry, b: &UnboxedEntry) -> bool {
+    let mut b = b.clone();
+    b.timestamp = a.timestamp;
+
+    super::same_entry(a, &b)
+}
+
+fn last_writer_wins(old: &UnboxedEntry, new: &UnboxedEntry) -> Resolution {
+    if new.timestamp > old.timestamp {
+        Resolution::Accept
+    } else if new.timestamp == old.timestamp {
+        Resolution::Conflict(String::from("the entries have the same timestamp"))
+    } else {
+        Resolution::Ignore
+    }
+}
+
+/// How many seconds `entry` is in the future, if that is more than
+/// `SYNC_MAX_CLOCK_SKEW` allows
+pub fn exceeds_clock_skew(entry: &UnboxedEntry) -> Option<u32> {
+    let max_skew = config!(SYNC_MAX_CLOCK_SKEW)?;
+    let ahead = entry.timestamp.saturating_sub(get_current_itelex_timestamp());
+
+    if u64::from(ahead) > max_skew.as_secs() {
+        Some(ahead)
+    } else {
+        None
+    }
+}
+
+/// Decide what to do with `new`, which `peer` sent us to replace `old`
+pub fn resolve(old: Option<&UnboxedEntry>, new: &UnboxedEntry, peer: SocketAddr) -> Resolution {
+    if quarantine::is_too_far_in_future(new) {
+        return Resolution::Quarantine;
+    }
+
+    if let Some(ahead) = exceeds_clock_skew(new) {
+        return Resolution::Conflict(format!("the timestamp is {} seconds in the future", ahead));
+    }
+
+    let old = match old {
+        Some(old) => old,
+        None => return Resolution::Accept,
+    };
+
+    if same_content(old, new) {
+        return if new.timestamp > old.timestamp { Resolution::Accept } else { Resolution::Ignore };
+    }
+
+    let last_source = history::last_source(old.number);
+
+    // NOTE: entries without a history are treated as coming from an unknown peer
+    let changed_locally = last_source
+        .as_ref()
+        .is_some_and(|source| !matches!(source, ChangeSource::Peer { .. }));
+
+    let last_peer = match last_source {
+        Some(ChangeSource::Peer { address }) => replication::peer_for_address(address),
+        _ => None,
+    };
+
+    match &config!(SYNC_CONFLICT_POLICY) {
+        ConflictPolicy::LastWriterWins => last_writer_wins(old, new),
+        ConflictPolicy::PreferLocal => {
+            if changed_locally {
+                Resolution::Conflict(String::from("the entry was changed on this server"))
+            } else {
+                last_writer_wins(old, new)
+            }
+        }
+        ConflictPolicy::PreferPeer(preferred) => {
+            if replication::peer_for_address(peer).as_ref() == Some(preferred) {
+                // NOTE: an older version from the preferred peer is one it did
+                // not get our newer version for yet, not a conflict it wins
+                if new.timestamp >= old.timestamp {
+                    Resolution::Accept
+                } else {
+                    Resolution::Ignore
+                }
+            } else if last_peer.as_ref() == Some(preferred) {
+                Resolution::Conflict(format!("the entry was last changed by {}", preferred))
+            } else {
+                last_writer_wins(old, new)
+            }
+        }
+    }
+}
+
+/// Log that `remote` from `peer` could not replace `local` because of `reason`
+#[allow(clippy::significant_drop_tightening)] // the conflicts are written while they are locked
+pub fn record_conflict(
+    local: Option<UnboxedEntry>,
+    remote: UnboxedEntry,
+    peer: SocketAddr,
+    reason: String,
+) -> anyhow::Result<()> {
+    let mut conflicts = lock_conflicts();
+
+    // NOTE: peers resend their entries on every full query
+    let already_recorded = conflicts.iter().any(|conflict| {
+        !conflict.resolved
+            && conflict.remote.number == remote.number
+            && super::same_entry(&conflict.remote, &remote)
+    });
+
+    if already_recorded {
+        return Ok(());
+    }
+
+    warn!("Conflict for entry {} from {}: {}", remote.number, peer, reason);
+
+    let id = conflicts.iter().map(|conflict| conflict.id + 1).max().unwrap_or(0);
+
+    // an older unresolved conflict of the peer about the same entry is superseded
+    conflicts.retain(|conflict| {
+        conflict.resolved || conflict.remote.number != remote.number || conflict.peer != peer
+    });
+
+    conflicts.push(ConflictRecord {
+        id,
+        time: get_current_itelex_timestamp(),
+        peer,
+        reason,
+        local,
+        remote,
+        resolved: false,
+    });
+
+    forget_resolved(&mut conflicts);
+
+    write_conflicts(&conflicts)
+}
+
+/// Remove the oldest resolved conflicts beyond `MAX_RESOLVED_CONFLICTS`
+fn forget_resolved(conflicts: &mut Vec<ConflictRecord>) {
+    let mut n_forgotten = conflicts
+        .iter()
+        .filter(|conflict| conflict.resolved)
+        .count()
+        .saturating_sub(MAX_RESOLVED_CONFLICTS);
+
+    conflicts.retain(|conflict| {
+        if n_forgotten > 0 && conflict.resolved {
+            n_forgotten -= 1;
+            false
+        } else {
+            true
+        }
+    });
+}
+
+/// All conflicts that have not been resolved yet, oldest first
+pub fn get_unresolved_conflicts() -> Vec<ConflictRecord> {
+    lock_conflicts().iter().filter(|conflict| !conflict.resolved).cloned().collect()
+}
+
+/// Resolve the conflict with `id` by writing the version of `winner` into the
+/// database.
+///
+/// The winning entry gets the current timestamp, so that it is sent to the
+/// other servers and replaces the version that lost there too.
+#[allow(clippy::significant_drop_tightening)] // the conflicts are written while they are locked
+pub fn resolve_conflict(id: u64, winner: Winner, source: ChangeSource) -> anyhow::Result<()> {
+    let conflict = lock_conflicts()
+        .iter()
+        .find(|conflict| conflict.id == id && !conflict.resolved)
+        .cloned()
+        .ok_or_else(|| anyhow!("there is no unresolved conflict with id {}", id))?;
+
+    let entry = match winner {
+        Winner::Local => conflict.local,
+        Winner::Remote => Some(conflict.remote),
+    };
+
+    if let Some(mut entry) = entry {
+        entry.pin = get_entry_by_number(entry.number)?.map_or(entry.pin, |current| current.pin);
+        entry.timestamp = get_current_itelex_timestamp();
+
+        info!("resolving conflict {} with the {:?} version", id, winner);
+
+        update_entry(Box::new(entry), source)?;
+    }
+
+    let mut conflicts = lock_conflicts();
+
+    if let Some(conflict) = conflicts.iter_mut().find(|conflict| conflict.id == id) {
+        conflict.resolved = true;
+    }
+
+    forget_resolved(&mut conflicts);
+
+    write_conflicts(&conflicts)
+}
@@ -0,0 +1,327 @@
+use super::{format, serialize_entry, Storage, RECORD_SIZE};
+use crate::{Entries, UnboxedEntry, CONFIG};
+use anyhow::Context;
+use dashmap::DashMap;
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+/// Keeps all entries in memory and writes them to `DB_PATH` when persisting.
+///
+/// Writes in between are appended to the journal at `DB_JOURNAL_PATH`, which
+/// is replayed when loading and compacted into `DB_PATH` when persisting.
+///
+/// The numbers of changed entries are appended to `DB_CHANGED_PATH` until
+/// they are cleared, so that they are not lost on a restart.
+pub struct FileStorage {
+    entries: DashMap<u32, UnboxedEntry>,
+    changed: DashMap<u32, ()>,
+
+    /// The open file of changed numbers, if it has been opened yet
+    changed_file: Mutex<Option<File>>,
+
+    /// The open journal file, if it has been opened yet.
+    ///
+    /// Every write to `entries` has to hold this lock, so that an entry is
+    /// never in the journal without being in `entries` or vice versa.
+    journal: Mutex<Option<File>>,
+}
+
+impl FileStorage {
+    pub fn new() -> Self {
+        Self {
+            entries: DashMap::new(),
+            changed: DashMap::new(),
+            changed_file: Mutex::new(None),
+            journal: Mutex::new(None),
+        }
+    }
+
+    fn lock_changed_file(&self) -> MutexGuard<'_, Option<File>> {
+        self.changed_file.lock().expect("the changed file lock was poisoned")
+    }
+
+    fn append_to_changed_file(changed_file: &mut Option<File>, number: u32) -> anyhow::Result<()> {
+        if config!(SERVER_PIN) == 0 {
+            // We never write to disk without a server pin (see `sync_db_to_disk`)
+            return Ok(());
+        }
+
+        if changed_file.is_none() {
+            *changed_file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&config!(DB_CHANGED_PATH))
+                    .context("Failed to open the changed file")?,
+            );
+        }
+
+        let file = changed_file.as_mut().unwrap();
+
+        file.write_all(format!("{}\n", number).as_bytes())
+            .context("Failed to append to the changed file")?;
+        file.sync_data().context("Failed to sync the changed file")?;
+
+        Ok(())
+    }
+
+    fn read_changed_file(&self) -> anyhow::Result<Vec<u32>> {
+        let changed_path = Path::new(&config!(DB_CHANGED_PATH));
+
+        if !changed_path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut numbers = Vec::new();
+
+        for line in std::fs::read_to_string(changed_path)?.lines() {
+            // NOTE: the last line may be incomplete if the server was stopped while writing it
+            match line.parse() {
+                Ok(number) => numbers.push(number),
+                Err(_) => warn!("Skipping broken line in the changed file: {:?}", line),
+            }
+        }
+
+        Ok(numbers)
+    }
+
+    fn lock_journal(&self) -> MutexGuard<'_, Option<File>> {
+        self.journal.lock().expect("the journal lock was poisoned")
+    }
+
+    fn append_to_journal(journal: &mut Option<File>, entry: &UnboxedEntry) -> anyhow::Result<()> {
+        if config!(SERVER_PIN) == 0 {
+            // We never write to disk without a server pin (see `sync_db_to_disk`)
+            return Ok(());
+        }
+
+        if journal.is_none() {
+            *journal = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&config!(DB_JOURNAL_PATH))
+                    .context("Failed to open the journal")?,
+            );
+        }
+
+        let file = journal.as_mut().unwrap();
+
+        // NOTE: we serialize into a buffer first, so that the entry is appended
+        // with a single write
+        let mut buffer = Vec::with_capacity(RECORD_SIZE);
+        serialize_entry(entry, &mut buffer)?;
+
+        file.write_all(&buffer).context("Failed to append to the journal")?;
+        file.sync_data().context("Failed to sync the journal")?;
+
+        Ok(())
+    }
+
+    fn read_journal(&self) -> anyhow::Result<Entries> {
+        let journal_path = Path::new(&config!(DB_JOURNAL_PATH));
+
+        if !journal_path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let buffer = std::fs::read(journal_path)?;
+
+        let complete_length = buffer.len() - buffer.len() % RECORD_SIZE;
+
+        if complete_length != buffer.len() {
+            warn!(
+                "The journal ends with an incomplete entry, which will be discarded. The server \
+                 was probably stopped while writing it."
+            );
+
+            // remove the incomplete entry, so that new entries are appended at the right offset
+            OpenOptions::new()
+                .write(true)
+                .open(journal_path)?
+                .set_len(complete_length as u64)
+                .context("Failed to truncate the journal")?;
+        }
+
+        format::read_unframed_entries(&buffer[..complete_length], config!(DB_RECOVERY_MODE))
+            .context("Failed to replay the journal")
+    }
+}
+
+impl Default for FileStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&self) -> anyhow::Result<()> {
+        let db_path = Path::new(&config!(DB_PATH));
+
+        let mut packages: Entries = if db_path.exists() {
+            let buffer = std::fs::read(db_path)?;
+
+            format::read_entries(&buffer, config!(DB_RECOVERY_MODE))?
+        } else {
+            warn!(
+                "The database could not be found on disk. It will be created on the next sync."
+            );
+
+            // We can't read a DB, because there is none, which is okay
+            Vec::new()
+        };
+
+        let journaled = self.read_journal()?;
+
+        info!("Replaying {} journaled entries", journaled.len());
+
+        // NOTE: journaled entries are newer than the ones in the snapshot
+        // and overwrite them when writing them to `entries` in order
+        packages.extend(journaled);
+
+        info!("Writing {} read entries to in memory DB", packages.len());
+
+        for package in packages {
+            self.entries.insert(package.number, package);
+        }
+
+        let changed = self.read_changed_file()?;
+
+        info!("Restoring {} changed entries", changed.len());
+
+        for number in changed {
+            self.changed.insert(number, ());
+        }
+
+        Ok(())
+    }
+
+    fn persist(&self) -> anyhow::Result<()> {
+        use std::fs::{copy, remove_file};
+
+        // NOTE: we hold the journal lock until the journal has been truncated,
+        // so that no write can happen between taking the snapshot and compacting
+        // the journal into it
+        let mut journal = self.lock_journal();
+
+        let temp_file: File = File::create(&config!(DB_PATH_TEMP))?;
+
+        let mut writer = format::DbWriter::new(BufWriter::new(temp_file))?;
+
+        for item in self.entries.iter() {
+            writer.write(item.value())?;
+        }
+
+        let temp_file: File = writer.finish()?.into_inner()?;
+
+        temp_file.sync_all()?;
+
+        drop(temp_file);
+
+        debug!("replacing database with temp file");
+
+        copy(&config!(DB_PATH_TEMP), &config!(DB_PATH))?;
+
+        remove_file(&config!(DB_PATH_TEMP))?;
+
+        // NOTE: we do not use rename here to make sure we only delete the temp file
+        // only gets deleted if we successfully copied it to the final file
+        // TODO: find out if this is really neccessary
+
+        debug!("truncating journal");
+
+        // the journal is reopened on the next write
+        *journal = None;
+        File::create(&config!(DB_JOURNAL_PATH))?.sync_all()?;
+
+        drop(journal);
+
+        Ok(())
+    }
+
+    fn get(&self, number: u32) -> anyhow::Result<Option<UnboxedEntry>> {
+        Ok(self.entries.get(&number).map(|item| item.value().clone()))
+    }
+
+    fn insert(&self, entry: UnboxedEntry) -> anyhow::Result<()> {
+        let mut journal = self.lock_journal();
+
+        Self::append_to_journal(&mut journal, &entry)?;
+
+        self.entries.insert(entry.number, entry);
+
+        drop(journal);
+
+        Ok(())
+    }
+
+    fn remove(&self, number: u32) -> anyhow::Result<()> {
+        // NOTE: the journal can't represent removals, so they are only
+        // stored by the next `persist`
+        let journal = self.lock_journal();
+
+        self.entries.remove(&number);
+
+        drop(journal);
+
+        Ok(())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&UnboxedEntry)) -> anyhow::Result<()> {
+        for item in self.entries.iter() {
+            f(item.value());
+        }
+
+        Ok(())
+    }
+
+    fn mark_changed(&self, number: u32) -> anyhow::Result<()> {
+        let mut changed_file = self.lock_changed_file();
+
+        if self.changed.insert(number, ()).is_none() {
+            Self::append_to_changed_file(&mut changed_file, number)?;
+        }
+
+        drop(changed_file);
+
+        Ok(())
+    }
+
+    fn peek_changed(&self) -> anyhow::Result<Vec<u32>> {
+        Ok(self.changed.iter().map(|item| *item.key()).collect())
+    }
+
+    fn clear_changed(&self, numbers: &[u32]) -> anyhow::Result<()> {
+        let mut changed_file = self.lock_changed_file();
+
+        for number in numbers {
+            self.changed.remove(number);
+        }
+
+        if config!(SERVER_PIN) != 0 {
+            // NOTE: the file is rewritten with the entries that are still
+            // changed and reopened on the next write
+            *changed_file = None;
+
+            let mut file = File::create(&config!(DB_CHANGED_PATH))?;
+
+            for item in self.changed.iter() {
+                file.write_all(format!("{}\n", item.key()).as_bytes())?;
+            }
+
+            file.sync_all()?;
+        }
+
+        drop(changed_file);
+
+        Ok(())
+    }
+
+    fn count_changed(&self) -> anyhow::Result<usize> {
+        Ok(self.changed.len())
+    }
+}
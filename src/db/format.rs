@@ -0,0 +1,288 @@
+//! The on-disk format of the database file.
+//!
+//! A database file consists of:
+//! - `MAGIC` followed by the format version as a little endian `u16`
+//! - one record per entry: the serialized `PeerReply` package followed by the
+//!   CRC32 of the package as a little endian `u32`
+//! - the number of records as a little endian `u32`
+//!
+//! Files written before the format was versioned consist of nothing but the
+//! serialized packages. They are still read and are migrated to the current
+//! format the next time the database is persisted.
+
+use super::{deserialize_entry, serialize_entry, RECORD_SIZE};
+use crate::{Entries, UnboxedEntry};
+use std::{convert::TryInto, io::Write, ops::Range};
+
+const MAGIC: &[u8; 8] = b"ITXTLNDB";
+const FORMAT_VERSION: u16 = 1;
+
+const HEADER_SIZE: usize = MAGIC.len() + 2;
+const CHECKSUM_SIZE: usize = 4;
+const TRAILER_SIZE: usize = 4;
+const FRAMED_RECORD_SIZE: usize = RECORD_SIZE + CHECKSUM_SIZE;
+
+fn checksum(record: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(record);
+    hasher.finalize()
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes.try_into().expect("read_u32 needs exactly 4 bytes"))
+}
+
+/// Reports a problem with the record at `offset`, returning an error unless
+/// we are in recovery mode, in which case the record is skipped
+fn corrupt_record(offset: usize, reason: &str, recover: bool) -> anyhow::Result<()> {
+    if recover {
+        error!("Skipping corrupt DB record at byte offset {}: {}", offset, reason);
+
+        Ok(())
+    } else {
+        bail!(anyhow!(
+            "DB record at byte offset {} is corrupt: {}. Set DB_RECOVERY_MODE=true to skip \
+             corrupt records.",
+            offset,
+            reason
+        ))
+    }
+}
+
+fn decode_records(
+    buffer: &[u8],
+    range: Range<usize>,
+    record_size: usize,
+    recover: bool,
+    entries: &mut Entries,
+) -> anyhow::Result<usize> {
+    let mut n_records = 0;
+
+    for (i, record) in buffer[range.clone()].chunks_exact(record_size).enumerate() {
+        let offset = range.start + i * record_size;
+
+        n_records += 1;
+
+        let (package, checksum_bytes) = record.split_at(RECORD_SIZE);
+
+        if !checksum_bytes.is_empty() && checksum(package) != read_u32(checksum_bytes) {
+            corrupt_record(offset, "checksum mismatch", recover)?;
+            continue;
+        }
+
+        match deserialize_entry(package) {
+            Ok(entry) => entries.push(entry),
+            Err(err) => corrupt_record(offset, &format!("{:?}", err), recover)?,
+        }
+    }
+
+    Ok(n_records)
+}
+
+/// Read the entries from the contents of a database file.
+///
+/// In recovery mode corrupt records are skipped and reported instead of
+/// failing the whole read.
+pub fn read_entries(buffer: &[u8], recover: bool) -> anyhow::Result<Entries> {
+    if !buffer.starts_with(MAGIC) {
+        warn!("The DB file uses the legacy format. It will be migrated on the next sync.");
+
+        return read_unframed_entries(buffer, recover);
+    }
+
+    let mut entries = Vec::new();
+
+    if buffer.len() < HEADER_SIZE + TRAILER_SIZE {
+        bail!(anyhow!("DB file is too short to contain a header and trailer"));
+    }
+
+    let version = u16::from_le_bytes([buffer[MAGIC.len()], buffer[MAGIC.len() + 1]]);
+
+    if version != FORMAT_VERSION {
+        bail!(anyhow!(
+            "DB file has format version {}, but only version {} is supported",
+            version,
+            FORMAT_VERSION
+        ));
+    }
+
+    let records_end = buffer.len() - TRAILER_SIZE;
+    let complete_end = records_end - (records_end - HEADER_SIZE) % FRAMED_RECORD_SIZE;
+
+    if complete_end != records_end {
+        corrupt_record(complete_end, "the file ends with an incomplete record", recover)?;
+    }
+
+    let n_records = decode_records(
+        buffer,
+        HEADER_SIZE..complete_end,
+        FRAMED_RECORD_SIZE,
+        recover,
+        &mut entries,
+    )?;
+
+    let expected_records = read_u32(&buffer[records_end..]) as usize;
+
+    if n_records != expected_records {
+        let reason = format!(
+            "the file should contain {} records, but contains {}. It may have been truncated",
+            expected_records, n_records
+        );
+
+        corrupt_record(records_end, &reason, recover)?;
+    }
+
+    if recover && entries.len() != expected_records {
+        error!(
+            "Recovered {} of {} DB records. The corrupt records will be dropped on the next sync.",
+            entries.len(),
+            expected_records
+        );
+    }
+
+    Ok(entries)
+}
+
+/// Read entries that were written as nothing but serialized packages, like
+/// legacy database files and the journal
+pub fn read_unframed_entries(buffer: &[u8], recover: bool) -> anyhow::Result<Entries> {
+    let mut entries = Vec::new();
+
+    let complete_length = buffer.len() - buffer.len() % RECORD_SIZE;
+
+    if complete_length != buffer.len() {
+        corrupt_record(complete_length, "the file ends with an incomplete record", recover)?;
+    }
+
+    decode_records(buffer, 0..complete_length, RECORD_SIZE, recover, &mut entries)?;
+
+    Ok(entries)
+}
+
+/// Writes entries in the current format
+pub struct DbWriter<W: Write> {
+    writer: W,
+    n_records: u32,
+    record: Vec<u8>,
+}
+
+impl<W: Write> DbWriter<W> {
+    pub fn new(mut writer: W) -> anyhow::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+
+        Ok(Self { writer, n_records: 0, record: Vec::with_capacity(RECORD_SIZE) })
+    }
+
+    pub fn write(&mut self, entry: &UnboxedEntry) -> anyhow::Result<()> {
+        self.record.clear();
+        serialize_entry(entry, &mut self.record)?;
+
+        self.writer.write_all(&self.record)?;
+        self.writer.write_all(&checksum(&self.record).to_le_bytes())?;
+
+        self.n_records += 1;
+
+        Ok(())
+    }
+
+    /// Write the trailer and return the underlying writer
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        self.writer.write_all(&self.n_records.to_le_bytes())?;
+
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{same_entry, test_entry};
+
+    fn write_file(entries: &[UnboxedEntry]) -> Vec<u8> {
+        let mut writer = DbWriter::new(Vec::new()).unwrap();
+
+        for entry in entries {
+            writer.write(entry).unwrap();
+        }
+
+        writer.finish().unwrap()
+    }
+
+    fn assert_same_entries(read: &[UnboxedEntry], expected: &[UnboxedEntry]) {
+        assert_eq!(read.len(), expected.len());
+
+        for (read, expected) in read.iter().zip(expected) {
+            assert!(same_entry(read, expected));
+        }
+    }
+
+    #[test]
+    fn reads_what_is_written() {
+        let entries = vec![test_entry(1, 10), test_entry(2, 20), test_entry(3, 30)];
+
+        let buffer = write_file(&entries);
+
+        assert_eq!(buffer.len(), HEADER_SIZE + 3 * FRAMED_RECORD_SIZE + TRAILER_SIZE);
+        assert_same_entries(&read_entries(&buffer, false).unwrap(), &entries);
+    }
+
+    #[test]
+    fn reads_empty_files() {
+        assert!(read_entries(&write_file(&[]), false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_legacy_files() {
+        let entries = vec![test_entry(1, 10), test_entry(2, 20)];
+
+        let mut buffer = Vec::new();
+
+        for entry in &entries {
+            serialize_entry(entry, &mut buffer).unwrap();
+        }
+
+        assert_same_entries(&read_entries(&buffer, false).unwrap(), &entries);
+    }
+
+    #[test]
+    fn detects_checksum_mismatches() {
+        let mut buffer = write_file(&[test_entry(1, 10), test_entry(2, 20)]);
+
+        buffer[HEADER_SIZE + 10] ^= 0xff;
+
+        assert!(read_entries(&buffer, false).is_err());
+        assert_same_entries(&read_entries(&buffer, true).unwrap(), &[test_entry(2, 20)]);
+    }
+
+    #[test]
+    fn detects_truncated_records() {
+        let buffer = write_file(&[test_entry(1, 10), test_entry(2, 20)]);
+
+        let truncated = &buffer[..HEADER_SIZE + FRAMED_RECORD_SIZE + FRAMED_RECORD_SIZE / 2];
+
+        assert!(read_entries(truncated, false).is_err());
+        assert_same_entries(&read_entries(truncated, true).unwrap(), &[test_entry(1, 10)]);
+    }
+
+    #[test]
+    fn detects_missing_trailers() {
+        let buffer = write_file(&[test_entry(1, 10), test_entry(2, 20)]);
+
+        let truncated = &buffer[..buffer.len() - TRAILER_SIZE];
+
+        assert!(read_entries(truncated, false).is_err());
+        assert_same_entries(&read_entries(truncated, true).unwrap(), &[test_entry(1, 10)]);
+    }
+
+    #[test]
+    fn detects_missing_records() {
+        let buffer = write_file(&[test_entry(1, 10), test_entry(2, 20)]);
+
+        let mut truncated = buffer[..HEADER_SIZE + FRAMED_RECORD_SIZE].to_vec();
+        truncated.extend_from_slice(&buffer[buffer.len() - TRAILER_SIZE..]);
+
+        assert!(read_entries(&truncated, false).is_err());
+        assert_same_entries(&read_entries(&truncated, true).unwrap(), &[test_entry(1, 10)]);
+    }
+}
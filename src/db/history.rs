@@ -0,0 +1,304 @@
+//! A log of every change to an entry, stored as one JSON object per line in
+//! `DB_HISTORY_PATH`.
+//!
+//! Once the file grows beyond `MAX_HISTORY_FILE_SIZE`, it is moved to
+//! `DB_HISTORY_PATH.1`, replacing the changes that were there, and a new file
+//! is started. Only the changes in these two files are kept.
+
+use super::{get_entry_by_number, update_entry};
+use crate::{get_current_itelex_timestamp, UnboxedEntry, CONFIG};
+use anyhow::Context;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs::{self, File, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+const MAX_HISTORY_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+static HISTORY: Lazy<DashMap<u32, Vec<HistoryRecord>>> = Lazy::new(DashMap::new);
+
+static HISTORY_FILE: Lazy<Mutex<Option<File>>> = Lazy::new(|| Mutex::new(None));
+
+/// Who made a change
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChangeSource {
+    /// A logged in user of the web interface, identified by a hash of their
+    /// session id
+    Web { session: String },
+    /// A teletype updating its address with a `ClientUpdate`
+    Client { address: IpAddr },
+    /// Another server syncing its entries to us
+    Peer { address: SocketAddr },
+    /// The server itself, e.g. when restoring a snapshot
+    Server,
+    /// An operator using the command line interface
+    Cli,
+}
+
+impl ChangeSource {
+    pub fn web_session(session_id: &str) -> Self {
+        // NOTE: we don't store the session id itself, since it can be used to log in
+        let mut hasher = DefaultHasher::new();
+        session_id.hash(&mut hasher);
+
+        Self::Web { session: format!("{:016x}", hasher.finish()) }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct HistoryRecord {
+    /// The itelex timestamp of the change
+    pub time: u32,
+    pub source: ChangeSource,
+    /// The entry before the change or `None` if it did not exist
+    pub old: Option<UnboxedEntry>,
+    pub new: UnboxedEntry,
+}
+
+impl HistoryRecord {
+    /// Leave out the pins of the entries, which are never recorded
+    ///
+    /// Returns whether there was a pin to leave out.
+    fn redact(&mut self) -> bool {
+        let had_pin = self.new.pin != 0 || self.old.as_ref().is_some_and(|old| old.pin != 0);
+
+        if let Some(old) = self.old.as_mut() {
+            old.pin = 0;
+        }
+
+        self.new.pin = 0;
+
+        had_pin
+    }
+}
+
+fn lock_history_file() -> MutexGuard<'static, Option<File>> {
+    HISTORY_FILE.lock().expect("the history file lock was poisoned")
+}
+
+fn rotated_history_path() -> String {
+    format!("{}.1", config!(DB_HISTORY_PATH))
+}
+
+/// Read the records in the file at `path` into memory
+///
+/// Returns the number of records read and whether any of them had a pin.
+fn read_history_file(path: &Path) -> anyhow::Result<(usize, bool)> {
+    if !path.exists() {
+        return Ok((0, false));
+    }
+
+    let mut n_records = 0;
+    let mut had_pins = false;
+
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+
+        match serde_json::from_str::<HistoryRecord>(&line) {
+            Ok(mut record) => {
+                had_pins |= record.redact();
+
+                HISTORY.entry(record.new.number).or_default().push(record);
+
+                n_records += 1;
+            }
+            Err(err) => warn!(
+                "Skipping broken history record in line {} of {}: {}",
+                i + 1,
+                path.display(),
+                err
+            ),
+        }
+    }
+
+    Ok((n_records, had_pins))
+}
+
+/// Replace the file at `path` with the records read from it, without pins
+///
+/// Older versions of the server recorded the pins of the entries.
+fn redact_history_file(path: &Path) -> anyhow::Result<()> {
+    let mut redacted = Vec::new();
+
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+
+        let line = match serde_json::from_str::<HistoryRecord>(&line) {
+            Ok(mut record) => {
+                record.redact();
+                serde_json::to_string(&record)?
+            }
+            // NOTE: broken records are kept as they are, since they are skipped anyway
+            Err(_) => line,
+        };
+
+        redacted.extend_from_slice(line.as_bytes());
+        redacted.push(b'\n');
+    }
+
+    let temp_path = path.with_extension("tmp");
+
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&redacted)?;
+    file.sync_all()?;
+
+    fs::rename(&temp_path, path)?;
+
+    Ok(())
+}
+
+pub fn load_history() -> anyhow::Result<()> {
+    let mut n_records = 0;
+
+    for path in &[rotated_history_path(), config!(DB_HISTORY_PATH).clone()] {
+        let path = Path::new(path);
+
+        let (n_read, had_pins) = read_history_file(path)?;
+        n_records += n_read;
+
+        // We never write to disk without a server pin (see `sync_db_to_disk`)
+        if had_pins && config!(SERVER_PIN) != 0 {
+            info!("Removing pins from {}", path.display());
+
+            redact_history_file(path)
+                .with_context(|| format!("Failed to remove pins from {}", path.display()))?;
+        }
+    }
+
+    info!("Read {} history records", n_records);
+
+    Ok(())
+}
+
+/// Move the history file to `DB_HISTORY_PATH.1` if it has grown too large and
+/// forget the changes that were there before
+fn rotate_history_file(history_file: &mut Option<File>) -> anyhow::Result<()> {
+    let history_path = &config!(DB_HISTORY_PATH);
+
+    let size = match fs::metadata(history_path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return Ok(()),
+    };
+
+    if size < MAX_HISTORY_FILE_SIZE {
+        return Ok(());
+    }
+
+    let rotated_path = rotated_history_path();
+
+    info!("Moving the history file to {}", rotated_path);
+
+    *history_file = None;
+
+    fs::rename(history_path, &rotated_path).context("Failed to move the history file")?;
+
+    HISTORY.clear();
+    read_history_file(Path::new(&rotated_path))?;
+
+    Ok(())
+}
+
+fn append_to_history_file(
+    history_file: &mut Option<File>,
+    record: &HistoryRecord,
+) -> anyhow::Result<()> {
+    if config!(SERVER_PIN) == 0 {
+        // We never write to disk without a server pin (see `sync_db_to_disk`)
+        return Ok(());
+    }
+
+    rotate_history_file(history_file)?;
+
+    if history_file.is_none() {
+        *history_file = Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&config!(DB_HISTORY_PATH))
+                .context("Failed to open the history file")?,
+        );
+    }
+
+    let file = history_file.as_mut().unwrap();
+
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+
+    file.write_all(&line)?;
+    file.sync_data().context("Failed to sync the history file")?;
+
+    Ok(())
+}
+
+/// Record that `old` was replaced by `new`.
+///
+/// Failing to record a change does not undo it, so errors are only logged.
+pub fn record_change(old: Option<UnboxedEntry>, new: &UnboxedEntry, source: ChangeSource) {
+    let mut record =
+        HistoryRecord { time: get_current_itelex_timestamp(), source, old, new: new.clone() };
+
+    record.redact();
+
+    // NOTE: we hold the lock until the record is in memory, so that a rotation
+    // can't read it from the file a second time
+    let mut history_file = lock_history_file();
+
+    if let Err(err) = append_to_history_file(&mut history_file, &record) {
+        error!("{:?}", err.context("Failed to record a change in the history"));
+    }
+
+    HISTORY.entry(new.number).or_default().push(record);
+}
+
+/// All recorded changes to the entry with `number`, oldest first
+pub fn get_history(number: u32) -> Vec<HistoryRecord> {
+    HISTORY.get(&number).map(|records| records.value().clone()).unwrap_or_default()
+}
+
+/// Who made the last recorded change to the entry with `number`
+pub fn last_source(number: u32) -> Option<ChangeSource> {
+    HISTORY.get(&number).and_then(|records| records.last().map(|record| record.source.clone()))
+}
+
+/// Write the version of the entry with `number` that was recorded at `time`
+/// with `timestamp` back into the database.
+///
+/// NOTE: versions are identified by these, since their positions in the
+/// history change when it is rotated.
+///
+/// The entry gets the current timestamp and is marked as changed, so that the
+/// revert is sent to the other servers. The current pin is kept.
+pub fn revert_entry(
+    number: u32,
+    time: u32,
+    timestamp: u32,
+    source: ChangeSource,
+) -> anyhow::Result<()> {
+    let mut entry = HISTORY
+        .get(&number)
+        .and_then(|records| {
+            records
+                .iter()
+                .rev()
+                .find(|record| record.time == time && record.new.timestamp == timestamp)
+                .map(|record| record.new.clone())
+        })
+        .ok_or_else(|| {
+            anyhow!("entry {} has no version from {} with timestamp {}", number, time, timestamp)
+        })?;
+
+    entry.pin = get_entry_by_number(number)?.map_or(0, |current| current.pin);
+    entry.timestamp = get_current_itelex_timestamp();
+
+    info!("reverting entry {} to its version from {} with timestamp {}", number, time, timestamp);
+
+    update_entry(Box::new(entry), source)
+}
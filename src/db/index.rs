@@ -0,0 +1,173 @@
+//! In-memory indexes over the stored entries, so that searches and filters
+//! don't have to go through every entry.
+//!
+//! They are updated by every write through this module and rebuilt when the
+//! entries are loaded.
+
+use super::search;
+use crate::{ClientType, UnboxedEntry};
+use once_cell::sync::Lazy;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+/// What an entry was indexed under, to remove it again
+#[derive(Debug)]
+struct Indexed {
+    tokens: Vec<String>,
+    client_type: u8,
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    entries: HashMap<u32, Indexed>,
+    /// The numbers of the entries by the normalized words of their names
+    name_tokens: HashMap<String, HashSet<u32>>,
+    client_types: HashMap<u8, HashSet<u32>>,
+    disabled: HashSet<u32>,
+}
+
+impl Index {
+    fn insert(&mut self, entry: &UnboxedEntry) {
+        self.remove(entry.number);
+
+        let tokens = search::tokens(&entry.name);
+
+        for token in &tokens {
+            self.name_tokens.entry(token.clone()).or_default().insert(entry.number);
+        }
+
+        let client_type = entry.client_type as u8;
+
+        self.client_types.entry(client_type).or_default().insert(entry.number);
+
+        if entry.disabled() {
+            self.disabled.insert(entry.number);
+        }
+
+        self.entries.insert(entry.number, Indexed { tokens, client_type });
+    }
+
+    fn remove(&mut self, number: u32) {
+        let indexed = match self.entries.remove(&number) {
+            Some(indexed) => indexed,
+            None => return,
+        };
+
+        for token in indexed.tokens {
+            if let Some(numbers) = self.name_tokens.get_mut(&token) {
+                numbers.remove(&number);
+
+                if numbers.is_empty() {
+                    self.name_tokens.remove(&token);
+                }
+            }
+        }
+
+        if let Some(numbers) = self.client_types.get_mut(&indexed.client_type) {
+            numbers.remove(&number);
+        }
+
+        self.disabled.remove(&number);
+    }
+
+    /// The numbers of the entries whose names contain every one of `words`
+    fn matching(&self, words: &[String]) -> HashSet<u32> {
+        let mut matching: Option<HashSet<u32>> = None;
+
+        for word in words {
+            // NOTE: words can't contain whitespace, so a name contains a word
+            // exactly if one of its tokens does. Since words may match anywhere
+            // in a token, every distinct token is checked, which are far fewer
+            // than the entries; a prefix index would only find token prefixes
+            let mut containing = HashSet::new();
+
+            for (token, numbers) in &self.name_tokens {
+                if token.contains(word.as_str()) {
+                    containing.extend(numbers);
+                }
+            }
+
+            matching = Some(match matching {
+                Some(matching) => matching.intersection(&containing).copied().collect(),
+                None => containing,
+            });
+        }
+
+        matching.unwrap_or_else(|| self.entries.keys().copied().collect())
+    }
+}
+
+static INDEX: Lazy<RwLock<Index>> = Lazy::new(|| RwLock::new(Index::default()));
+
+fn read_index() -> RwLockReadGuard<'static, Index> {
+    INDEX.read().expect("the index lock was poisoned")
+}
+
+fn write_index() -> RwLockWriteGuard<'static, Index> {
+    INDEX.write().expect("the index lock was poisoned")
+}
+
+/// Index `entries` instead of what was indexed before
+pub fn rebuild(entries: &[UnboxedEntry]) {
+    let mut index = Index::default();
+
+    for entry in entries {
+        index.insert(entry);
+    }
+
+    *write_index() = index;
+}
+
+pub fn insert(entry: &UnboxedEntry) {
+    write_index().insert(entry);
+}
+
+pub fn remove(number: u32) {
+    write_index().remove(number);
+}
+
+/// What to look up in the index. Every given condition has to match
+#[derive(Debug, Default)]
+pub struct Query<'a> {
+    /// Normalized words that have to be contained in the name
+    pub words: Option<&'a [String]>,
+    pub client_type: Option<u8>,
+    pub disabled: Option<bool>,
+    /// Leave out disabled and deleted entries
+    pub public_only: bool,
+}
+
+/// The numbers of the entries matching `query`, in ascending order
+#[allow(clippy::significant_drop_tightening)] // the index is read until the end
+pub fn find(query: &Query) -> Vec<u32> {
+    let index = read_index();
+
+    let mut numbers: BTreeSet<u32> = query.words.map_or_else(
+        || index.entries.keys().copied().collect(),
+        |words| index.matching(words).into_iter().collect(),
+    );
+
+    if let Some(client_type) = query.client_type {
+        let empty = HashSet::new();
+        let with_type = index.client_types.get(&client_type).unwrap_or(&empty);
+
+        numbers.retain(|number| with_type.contains(number));
+    }
+
+    if let Some(disabled) = query.disabled {
+        numbers.retain(|number| index.disabled.contains(number) == disabled);
+    }
+
+    if query.public_only {
+        let deleted = index.client_types.get(&(ClientType::Deleted as u8));
+
+        numbers.retain(|number| {
+            !index.disabled.contains(number)
+                && !deleted.is_some_and(|deleted| deleted.contains(number))
+        });
+    }
+
+    numbers.into_iter().collect()
+}
@@ -0,0 +1,177 @@
+//! Import of the `teilnehmer` table of the SQLite database used by the
+//! Node.js teilnehmerserver, which this server replaces.
+
+use super::{
+    transfer::{ImportReport, RowError},
+    update_entry_if_newer, ChangeSource,
+};
+use crate::{ClientType, UnboxedEntry};
+use anyhow::Context;
+use rusqlite::{types::Value, Connection, OpenFlags, NO_PARAMS};
+
+/// The number of seconds between the itelex epoch (1900) and the unix epoch.
+///
+/// The legacy server stores unix timestamps, while we use itelex timestamps.
+const UNIX_TO_ITELEX_OFFSET: u32 = 60 * 60 * 24 * (365 * 70 + 17);
+
+/// A row of the legacy `teilnehmer` table, whose columns are all nullable
+struct LegacyRow {
+    number: Option<u32>,
+    name: Option<String>,
+    client_type: Option<i64>,
+    hostname: Option<String>,
+    ipaddress: Option<String>,
+    port: Option<u16>,
+    extension: Value,
+    pin: Option<u16>,
+    disabled: Option<bool>,
+    timestamp: Option<u32>,
+}
+
+/// Convert an extension as the legacy server stores it (e.g. `"0"`, `"07"`,
+/// `"42"`) into its itelex code.
+///
+/// Numeric extensions are assumed to already be itelex codes.
+fn parse_extension(extension: &Value) -> anyhow::Result<u8> {
+    let extension = match extension {
+        Value::Null => return Ok(0),
+        Value::Integer(code @ 0..=110) => return Ok(*code as u8),
+        Value::Text(extension) => extension.trim(),
+        _ => bail!(anyhow!("invalid extension: {:?}", extension)),
+    };
+
+    if extension.is_empty() || extension == "-" {
+        return Ok(0);
+    }
+
+    let value: u8 =
+        extension.parse().with_context(|| format!("invalid extension: `{}`", extension))?;
+
+    Ok(match (extension.len(), value) {
+        (1, 0) => 110,
+        (1, _) => 100 + value,
+        (2, 0) => 100,
+        (2, _) => value,
+
+        _ => bail!(anyhow!("invalid extension: `{}`", extension)),
+    })
+}
+
+impl LegacyRow {
+    fn into_entry(self) -> anyhow::Result<UnboxedEntry> {
+        let number = self.number.ok_or_else(|| anyhow!("entry has no number"))?;
+
+        let client_type = self.client_type.unwrap_or(0);
+
+        let client_type: ClientType = serde_json::from_value(client_type.into())
+            .map_err(|_| anyhow!("entry {} has an unknown client type: {}", number, client_type))?;
+
+        // NOTE: we go through the same JSON representation the web interface uses
+        serde_json::from_value(serde_json::json!({
+            "number": number,
+            "name": self.name.unwrap_or_default(),
+            "client_type": client_type as u8,
+            "hostname": self.hostname.filter(|hostname| !hostname.is_empty()),
+            "ipaddress": self.ipaddress.filter(|ipaddress| !ipaddress.is_empty()),
+            "port": self.port.unwrap_or(0),
+            "extension": parse_extension(&self.extension)?,
+            "flags": if self.disabled.unwrap_or(false) { 2 } else { 0 },
+            "timestamp": self.timestamp.unwrap_or(0).saturating_add(UNIX_TO_ITELEX_OFFSET),
+            "pin": self.pin.unwrap_or(0),
+        }))
+        .with_context(|| format!("entry {} could not be converted", number))
+    }
+}
+
+/// Import all entries of the legacy database at `path` that are newer than
+/// the ones we have, reporting every row that could not be converted.
+///
+/// Unlike other imports, the pins of the legacy entries are imported too, so
+/// that the teletypes can keep updating their addresses.
+pub fn import_legacy_database(path: &str) -> anyhow::Result<ImportReport> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Failed to open {}", path))?;
+
+    let mut statement = connection.prepare(
+        "SELECT number, name, type, hostname, ipaddress, port, extension, pin, disabled, \
+         timestamp FROM teilnehmer",
+    )?;
+
+    let rows = statement
+        .query_map(NO_PARAMS, |row| {
+            Ok(LegacyRow {
+                number: row.get(0)?,
+                name: row.get(1)?,
+                client_type: row.get(2)?,
+                hostname: row.get(3)?,
+                ipaddress: row.get(4)?,
+                port: row.get(5)?,
+                extension: row.get(6)?,
+                pin: row.get(7)?,
+                disabled: row.get(8)?,
+                timestamp: row.get(9)?,
+            })
+        })?
+        .collect::<Vec<_>>();
+
+    let mut report = ImportReport { n_rows: rows.len(), ..Default::default() };
+
+    for (i, row) in rows.into_iter().enumerate() {
+        let result = row
+            .map_err(anyhow::Error::from)
+            .and_then(LegacyRow::into_entry)
+            .and_then(|entry| update_entry_if_newer(Box::new(entry), ChangeSource::Cli));
+
+        match result {
+            Ok(true) => report.n_imported += 1,
+            Ok(false) => {}
+            Err(err) => report.errors.push(RowError { row: i + 1, error: format!("{:#}", err) }),
+        }
+    }
+
+    info!(
+        "imported {} of {} legacy entries with {} errors",
+        report.n_imported,
+        report.n_rows,
+        report.errors.len()
+    );
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(extension: &str) -> u8 {
+        parse_extension(&Value::Text(String::from(extension))).unwrap()
+    }
+
+    #[test]
+    fn parses_extensions() {
+        assert_eq!(parse("0"), 110);
+        assert_eq!(parse("5"), 105);
+        assert_eq!(parse("00"), 100);
+        assert_eq!(parse("42"), 42);
+        assert_eq!(parse(" 7 "), 107);
+        assert_eq!(parse("-"), 0);
+        assert_eq!(parse(""), 0);
+    }
+
+    #[test]
+    fn parses_extension_codes_and_null() {
+        assert_eq!(parse_extension(&Value::Null).unwrap(), 0);
+        assert_eq!(parse_extension(&Value::Integer(42)).unwrap(), 42);
+        assert_eq!(parse_extension(&Value::Integer(110)).unwrap(), 110);
+    }
+
+    #[test]
+    fn rejects_invalid_extensions() {
+        for extension in &["123", "a", "4a"] {
+            assert!(parse_extension(&Value::Text(String::from(*extension))).is_err());
+        }
+
+        assert!(parse_extension(&Value::Integer(111)).is_err());
+        assert!(parse_extension(&Value::Real(1.0)).is_err());
+    }
+}
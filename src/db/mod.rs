@@ -0,0 +1,607 @@
+mod file_storage;
+pub mod conflicts;
+pub mod format;
+pub mod history;
+mod index;
+pub mod legacy;
+pub mod outbox;
+pub mod peers;
+pub mod quarantine;
+pub mod replication;
+pub mod search;
+pub mod snapshots;
+mod sqlite_storage;
+mod storage;
+pub mod transfer;
+mod validation;
+
+pub use file_storage::FileStorage;
+pub use history::ChangeSource;
+pub use sqlite_storage::SqliteStorage;
+pub use storage::{Storage, StorageBackend};
+pub use validation::{validate_entry, FieldError, ValidationError};
+
+use crate::{
+    errors::ItelexServerErrorKind, get_current_itelex_timestamp, packages::*, Entries, Entry,
+    UnboxedEntry, CONFIG,
+};
+use anyhow::Context;
+use once_cell::sync::{Lazy, OnceCell};
+use std::{
+    io::{Cursor, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Mutex as SyncMutex, MutexGuard},
+};
+use tokio::sync::Mutex;
+
+/// The size of a serialized `PeerReply` package, including its header
+const RECORD_SIZE: usize = 100;
+
+static FS_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+static STORAGE: OnceCell<Box<dyn Storage>> = OnceCell::new();
+
+/// Held while reading an entry that is about to be updated, so that no other
+/// task can change the entry in between
+static WRITE_LOCK: Lazy<SyncMutex<()>> = Lazy::new(|| SyncMutex::new(()));
+
+fn lock_writes() -> MutexGuard<'static, ()> {
+    WRITE_LOCK.lock().expect("the write lock was poisoned")
+}
+
+fn serialize_entry(entry: &UnboxedEntry, writer: &mut impl Write) -> anyhow::Result<()> {
+    use itelex::PackageBody;
+
+    entry.serialize(writer)?;
+
+    Ok(())
+}
+
+fn deserialize_entry(record: &[u8]) -> anyhow::Result<UnboxedEntry> {
+    use itelex::Package;
+
+    Ok(Package::<Server>::deserialize(&mut Cursor::new(record))?
+        .downcast_ref::<PeerReply>()
+        .ok_or_else(|| anyhow!("DB had a broken package"))?
+        .clone())
+}
+
+/// Replace the JSON file at `path` with `value`, making sure it is either
+/// completely written or not at all
+fn write_json_file(path: &str, value: &impl serde::Serialize) -> anyhow::Result<()> {
+    use std::{fs, io::BufWriter};
+
+    if config!(SERVER_PIN) == 0 {
+        // We never write to disk without a server pin (see `sync_db_to_disk`)
+        return Ok(());
+    }
+
+    let temp_path = format!("{}.temp", path);
+
+    let mut writer = BufWriter::new(fs::File::create(&temp_path)?);
+
+    serde_json::to_writer(&mut writer, value)?;
+
+    writer.flush()?;
+    writer.into_inner()?.sync_all()?;
+
+    fs::rename(&temp_path, path).with_context(|| format!("Failed to replace {}", path))?;
+
+    Ok(())
+}
+
+/// Compare two entries by their serialized representation
+fn same_entry(a: &UnboxedEntry, b: &UnboxedEntry) -> bool {
+    let (mut a_buffer, mut b_buffer) = (Vec::new(), Vec::new());
+
+    serialize_entry(a, &mut a_buffer).is_ok()
+        && serialize_entry(b, &mut b_buffer).is_ok()
+        && a_buffer == b_buffer
+}
+
+pub fn init_storage() -> anyhow::Result<()> {
+    info!("Using {:?} storage backend", config!(DB_BACKEND));
+
+    let storage: Box<dyn Storage> = match config!(DB_BACKEND) {
+        StorageBackend::File => Box::new(FileStorage::new()),
+        StorageBackend::Sqlite => Box::new(open_sqlite_storage()?),
+    };
+
+    if STORAGE.set(storage).is_err() {
+        bail!(anyhow!("The storage was already initialized"));
+    }
+
+    Ok(())
+}
+
+/// Open the SQLite database, filling it with the entries stored by the file
+/// backend if it is created and there are any
+fn open_sqlite_storage() -> anyhow::Result<SqliteStorage> {
+    use std::{fs, path::Path};
+
+    let sqlite_path = &config!(DB_SQLITE_PATH);
+
+    let created = !Path::new(sqlite_path).exists();
+
+    let storage = SqliteStorage::open(sqlite_path)?;
+
+    if created && Path::new(&config!(DB_PATH)).exists() {
+        if let Err(err) = import_file_storage(&storage) {
+            drop(storage);
+
+            // NOTE: otherwise the import would not be tried again on the next start
+            if config!(SERVER_PIN) != 0 {
+                fs::remove_file(sqlite_path)?;
+            }
+
+            bail!(err.context("Failed to import the entries of the file backend"));
+        }
+    }
+
+    Ok(storage)
+}
+
+/// Copy the entries stored by the file backend into `storage`
+fn import_file_storage(storage: &dyn Storage) -> anyhow::Result<()> {
+    info!("Importing the entries in {:?} into the SQLite database", config!(DB_PATH));
+
+    let file_storage = FileStorage::new();
+
+    file_storage.load()?;
+
+    for entry in file_storage.entries()? {
+        storage.insert(entry)?;
+    }
+
+    for number in file_storage.peek_changed()? {
+        storage.mark_changed(number)?;
+    }
+
+    Ok(())
+}
+
+pub fn storage() -> &'static dyn Storage {
+    STORAGE.get().expect("The storage was not initialized").as_ref()
+}
+
+/// Insert `entry` into the storage and the indexes
+fn store_entry(entry: UnboxedEntry) -> anyhow::Result<()> {
+    storage().insert(entry.clone())?;
+
+    index::insert(&entry);
+
+    Ok(())
+}
+
+/// Remove the entry with `number` from the storage and the indexes
+fn remove_stored_entry(number: u32) -> anyhow::Result<()> {
+    storage().remove(number)?;
+
+    index::remove(number);
+
+    Ok(())
+}
+
+pub async fn sync_db_to_disk() -> anyhow::Result<()> {
+    if config!(SERVER_PIN) == 0 {
+        warn!("Refused to sync DB to disk, so that no important data is overwritten.");
+
+        return Ok(());
+    }
+
+    let fs_lock = FS_LOCK.lock().await;
+
+    info!("Syncing DB to disk");
+
+    storage().persist()?;
+
+    replication::write_replication_state()?;
+
+    let snapshot = snapshots::create_snapshot().context("Failed to create a snapshot")?;
+
+    drop(fs_lock);
+
+    info!("Synced Database (snapshot {})", snapshot);
+
+    Ok(())
+}
+
+pub async fn read_db_from_disk() -> anyhow::Result<()> {
+    info!("Reading entries from disk");
+
+    let fs_lock = FS_LOCK.lock().await;
+
+    storage().load()?;
+
+    index::rebuild(&storage().entries()?);
+
+    history::load_history().context("Failed to read the history")?;
+
+    outbox::load_outbox()?;
+
+    peers::load_peers()?;
+
+    replication::load_replication_state()?;
+
+    conflicts::load_conflicts()?;
+
+    quarantine::load_quarantine()?;
+
+    if config!(SERVER_PIN) == 0 {
+        warn!(
+            "Removing pins from read DB entries and removing private ones as to not leak them, \
+             since we are running without a SERVER_PIN"
+        );
+
+        for mut entry in storage().entries()? {
+            if entry.disabled() {
+                remove_stored_entry(entry.number)?;
+            } else {
+                entry.pin = 0;
+
+                store_entry(entry)?;
+            }
+        }
+    }
+
+    drop(fs_lock);
+
+    info!("Finished reading DB");
+
+    Ok(())
+}
+
+/// The entries that were changed since they were last handed on to be sent to
+/// the other servers. They stay marked as changed until `clear_changed_entries`
+pub fn get_changed_entries() -> anyhow::Result<Entries> {
+    let mut changed_entries: Entries = Vec::new();
+    let mut removed = Vec::new();
+
+    for number in storage().peek_changed()? {
+        match storage().get(number)? {
+            Some(entry) => changed_entries.push(entry),
+            None => removed.push(number),
+        }
+    }
+
+    // there is nothing to send for entries that don't exist anymore
+    if !removed.is_empty() {
+        storage().clear_changed(&removed)?;
+    }
+
+    debug!("changed entries: {:#?}", changed_entries);
+
+    Ok(changed_entries)
+}
+
+/// Unmark the `sent` entries as changed, unless they were changed again since
+/// they were read by `get_changed_entries`
+pub fn clear_changed_entries(sent: &[UnboxedEntry]) -> anyhow::Result<()> {
+    let write_lock = lock_writes();
+
+    let mut numbers = Vec::with_capacity(sent.len());
+
+    for entry in sent {
+        if let Some(stored) = storage().get(entry.number)? {
+            if same_entry(&stored, entry) {
+                numbers.push(entry.number);
+            }
+        }
+    }
+
+    storage().clear_changed(&numbers)?;
+
+    drop(write_lock);
+
+    Ok(())
+}
+
+pub fn count_changed_entries() -> anyhow::Result<usize> {
+    storage().count_changed()
+}
+
+pub fn get_all_entries() -> anyhow::Result<Entries> {
+    storage().entries()
+}
+
+pub fn update_or_register_entry(package: ClientUpdate, ipaddress: Ipv4Addr) -> anyhow::Result<()> {
+    // Confirm that ipaddress is not unspecified, since this could lead to entries
+    // with neither an ip nor a hostname
+    if ipaddress.is_unspecified() {
+        bail!(ItelexServerErrorKind::UserInputError);
+    }
+
+    let number = package.number;
+
+    let new_entry = PeerReply {
+        client_type: ClientType::BaudotDynIp,
+        flags: PeerReply::flags(true),
+        extension: 0,
+        hostname: "".into(),
+        ipaddress,
+        name: "?".into(),
+        number,
+        pin: package.pin,
+        port: package.port,
+        timestamp: get_current_itelex_timestamp(),
+    };
+
+    let write_lock = lock_writes();
+
+    let old_entry = storage().get(number)?;
+
+    let entry = if let Some(mut existing) = old_entry.clone() {
+        if existing.client_type == ClientType::Deleted {
+            new_entry
+        } else if existing.client_type == ClientType::BaudotDynIp {
+            if existing.pin == 0 {
+                // NOTE: overwrite 0 pins.
+                existing.pin = package.pin;
+
+                warn!("overwrote a 0 pin.");
+            }
+
+            if package.pin == existing.pin {
+                existing.ipaddress = ipaddress;
+                existing.timestamp = get_current_itelex_timestamp();
+
+                existing
+            } else {
+                bail!(ItelexServerErrorKind::PasswordError);
+            }
+        } else {
+            bail!(ItelexServerErrorKind::InvalidClientType(
+                existing.client_type,
+                ClientType::BaudotDynIp
+            ));
+        }
+    } else {
+        new_entry
+    };
+
+    store_entry(entry.clone())?;
+
+    storage().mark_changed(number)?;
+
+    history::record_change(old_entry, &entry, ChangeSource::Client {
+        address: IpAddr::V4(ipaddress),
+    });
+
+    drop(write_lock);
+
+    Ok(())
+}
+
+#[allow(clippy::boxed_local)]
+pub fn update_entry(entry: Entry, source: ChangeSource) -> anyhow::Result<()> {
+    validate_entry(&entry)?;
+
+    let write_lock = lock_writes();
+
+    let number = entry.number;
+
+    let old_entry = storage().get(number)?;
+
+    store_entry(*entry.clone())?;
+
+    storage().mark_changed(number)?;
+
+    history::record_change(old_entry, &entry, source);
+
+    drop(write_lock);
+
+    Ok(())
+}
+
+/// Update the entry if it is newer than the stored one, returning whether it
+/// was
+#[allow(clippy::boxed_local)]
+pub fn update_entry_if_newer(entry: Entry, source: ChangeSource) -> anyhow::Result<bool> {
+    validate_entry(&entry)?;
+
+    // NOTE: we hold the write lock while checking the timestamp,
+    // so that no other task can change the entry we just checked
+    let write_lock = lock_writes();
+
+    let old_entry = storage().get(entry.number)?;
+
+    let do_update = old_entry.as_ref().map_or(true, |old| old.timestamp < entry.timestamp);
+
+    if do_update {
+        let number = entry.number;
+
+        store_entry(*entry.clone())?;
+
+        storage().mark_changed(number)?;
+
+        history::record_change(old_entry, &entry, source);
+    }
+
+    drop(write_lock);
+
+    Ok(do_update)
+}
+
+/// Merge an entry `peer` sent us into the database according to the
+/// `SYNC_CONFLICT_POLICY`, returning whether it was written
+#[allow(clippy::boxed_local)]
+pub fn update_entry_from_peer(entry: Entry, peer: SocketAddr) -> anyhow::Result<bool> {
+    validate_entry(&entry)?;
+
+    // NOTE: we hold the write lock while resolving, so that no other task can
+    // change the entry we are comparing against
+    let write_lock = lock_writes();
+
+    let old_entry = storage().get(entry.number)?;
+
+    let do_update = match conflicts::resolve(old_entry.as_ref(), &entry, peer) {
+        conflicts::Resolution::Accept => true,
+        conflicts::Resolution::Ignore => false,
+        conflicts::Resolution::Conflict(reason) => {
+            // NOTE: failing to log a conflict is not a reason to abort the sync
+            if let Err(err) =
+                conflicts::record_conflict(old_entry.clone(), *entry.clone(), peer, reason)
+            {
+                error!("{:?}", err.context("Failed to record a conflict"));
+            }
+
+            false
+        }
+        conflicts::Resolution::Quarantine => {
+            quarantine::quarantine_entry(*entry.clone(), peer)?;
+
+            false
+        }
+    };
+
+    if do_update {
+        let number = entry.number;
+
+        store_entry(*entry.clone())?;
+
+        storage().mark_changed(number)?;
+
+        history::record_change(old_entry, &entry, ChangeSource::Peer { address: peer });
+    }
+
+    drop(write_lock);
+
+    Ok(do_update)
+}
+
+/// Reset the pin of the entry with `number`, returning `false` if there is no
+/// such entry
+pub fn reset_pin(number: u32, source: ChangeSource) -> anyhow::Result<bool> {
+    let write_lock = lock_writes();
+
+    let old_entry = storage().get(number)?;
+
+    let found = if let Some(mut entry) = old_entry.clone() {
+        entry.pin = 0;
+
+        store_entry(entry.clone())?;
+
+        history::record_change(old_entry, &entry, source);
+
+        true
+    } else {
+        false
+    };
+
+    drop(write_lock);
+
+    Ok(found)
+}
+
+fn is_public(entry: &UnboxedEntry) -> bool {
+    !(entry.disabled() || entry.client_type == ClientType::Deleted)
+}
+
+const fn sanitize_entry(mut entry: UnboxedEntry) -> UnboxedEntry {
+    entry.pin = 0;
+    entry.flags &= 2;
+    entry
+}
+
+/// What `find_entries` looks for. Every given condition has to match
+#[derive(Debug, Default)]
+pub struct EntryFilter<'a> {
+    pub pattern: Option<&'a str>,
+    pub client_type: Option<u8>,
+    pub disabled: Option<bool>,
+    /// Leave out disabled and deleted entries
+    pub public_only: bool,
+}
+
+/// The sanitized entries matching `filter`, which are looked up in the
+/// indexes instead of going through every entry
+pub fn find_entries(filter: &EntryFilter) -> anyhow::Result<Entries> {
+    if filter.pattern.is_none() && filter.client_type.is_none() && filter.disabled.is_none() {
+        // NOTE: the indexes would not narrow this down, and reading every entry
+        // at once is cheaper than looking them up one by one
+        return Ok(storage()
+            .entries()?
+            .into_iter()
+            .filter(|entry| !filter.public_only || is_public(entry))
+            .map(sanitize_entry)
+            .collect());
+    }
+
+    let words = filter.pattern.map(search::tokens);
+
+    let numbers = index::find(&index::Query {
+        words: words.as_deref(),
+        client_type: filter.client_type,
+        disabled: filter.disabled,
+        public_only: filter.public_only,
+    });
+
+    let mut entries: Entries = Vec::with_capacity(numbers.len());
+
+    for number in numbers {
+        if let Some(entry) = storage().get(number)? {
+            // NOTE: the entry might have changed since it was looked up
+            if !filter.public_only || is_public(&entry) {
+                entries.push(sanitize_entry(entry));
+            }
+        }
+    }
+
+    Ok(match filter.pattern {
+        Some(pattern) => search::search_entries(entries, pattern),
+        None => entries,
+    })
+}
+
+pub fn get_public_entries() -> anyhow::Result<Entries> {
+    find_entries(&EntryFilter { public_only: true, ..EntryFilter::default() })
+}
+
+pub fn get_sanitized_entries() -> anyhow::Result<Entries> {
+    Ok(storage().entries()?.into_iter().map(sanitize_entry).collect())
+}
+
+pub fn get_public_entries_by_pattern(pattern: &str) -> anyhow::Result<Entries> {
+    find_entries(&EntryFilter {
+        pattern: Some(pattern),
+        public_only: true,
+        ..EntryFilter::default()
+    })
+}
+
+pub fn get_entry_by_number(number: u32) -> anyhow::Result<Option<Entry>> {
+    Ok(storage().get(number)?.map(Box::new))
+}
+
+pub fn get_public_entry_by_number(number: u32) -> anyhow::Result<Option<Entry>> {
+    match storage().get(number)? {
+        Some(entry) => {
+            let mut entry: Entry = Box::new(entry);
+
+            if !is_public(&entry) {
+                dbg!(&entry);
+                return Ok(None);
+            }
+            entry.pin = 0;
+            Ok(Some(entry))
+        }
+        None => Ok(None),
+    }
+}
+
+/// An entry of a teletype with a fixed ip address, for tests
+#[cfg(test)]
+fn test_entry(number: u32, timestamp: u32) -> UnboxedEntry {
+    PeerReply {
+        client_type: ClientType::BaudotFixedIp,
+        flags: PeerReply::flags(false),
+        extension: 0,
+        hostname: "".into(),
+        ipaddress: Ipv4Addr::new(192, 0, 2, 1),
+        name: "Test".into(),
+        number,
+        pin: 0,
+        port: 134,
+        timestamp,
+    }
+}
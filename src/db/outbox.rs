@@ -0,0 +1,183 @@
+//! The entries that still have to be sent to each peer server, which are
+//! stored in `DB_OUTBOX_PATH`, so that they are sent even if the server is
+//! restarted while a peer is unreachable.
+//!
+//! Only the newest version of every entry is kept, so that the outbox of an
+//! unreachable peer grows at most to the size of the directory.
+
+use super::write_json_file;
+use crate::{Entries, UnboxedEntry, CONFIG};
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+/// The queued entries by their number, by the host of their peer
+type Outbox = HashMap<String, BTreeMap<u32, UnboxedEntry>>;
+
+/// Read the outbox from `bytes`. It used to be stored as a list of entries
+/// per peer, which is still read
+fn parse_outbox(bytes: &[u8]) -> anyhow::Result<Outbox> {
+    let err = match serde_json::from_slice::<Outbox>(bytes) {
+        Ok(outbox) => return Ok(outbox),
+        Err(err) => err,
+    };
+
+    let lists: HashMap<String, Entries> = serde_json::from_slice(bytes).map_err(|_| err)?;
+
+    Ok(lists
+        .into_iter()
+        .map(|(peer, entries)| {
+            let mut queue = BTreeMap::new();
+            insert_newer(&mut queue, entries);
+
+            (peer, queue)
+        })
+        .collect())
+}
+
+/// Put `entries` into `queue`, unless it already contains a newer version
+fn insert_newer(
+    queue: &mut BTreeMap<u32, UnboxedEntry>,
+    entries: impl IntoIterator<Item = UnboxedEntry>,
+) {
+    for entry in entries {
+        match queue.get(&entry.number) {
+            Some(queued) if queued.timestamp > entry.timestamp => {}
+            _ => {
+                queue.insert(entry.number, entry);
+            }
+        }
+    }
+}
+
+static OUTBOX: Lazy<Mutex<Outbox>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn lock_outbox() -> MutexGuard<'static, Outbox> {
+    OUTBOX.lock().expect("the outbox lock was poisoned")
+}
+
+pub fn load_outbox() -> anyhow::Result<()> {
+    let outbox_path = Path::new(&config!(DB_OUTBOX_PATH));
+
+    if !outbox_path.exists() {
+        return Ok(());
+    }
+
+    let outbox = parse_outbox(&fs::read(outbox_path)?).context("Failed to read the outbox")?;
+
+    for (peer, entries) in &outbox {
+        if !entries.is_empty() {
+            info!("{} entries are waiting to be sent to {}", entries.len(), peer);
+        }
+    }
+
+    *lock_outbox() = outbox;
+
+    Ok(())
+}
+
+fn write_outbox(outbox: &Outbox) -> anyhow::Result<()> {
+    write_json_file(&config!(DB_OUTBOX_PATH), outbox).context("Failed to write the outbox")
+}
+
+/// Queue `entries` to be sent to every server in `peers`
+#[allow(clippy::significant_drop_tightening)] // the outbox is written while it is locked
+pub fn enqueue(peers: &[String], entries: &[UnboxedEntry]) -> anyhow::Result<()> {
+    let mut outbox = lock_outbox();
+
+    for peer in peers {
+        insert_newer(outbox.entry(peer.clone()).or_default(), entries.iter().cloned());
+    }
+
+    write_outbox(&outbox)
+}
+
+/// The entries that still have to be sent to `peer`, oldest first
+pub fn pending(peer: &str) -> Entries {
+    let mut entries: Entries =
+        lock_outbox().get(peer).map_or_else(Vec::new, |queue| queue.values().cloned().collect());
+
+    entries.sort_by_key(|entry| entry.timestamp);
+
+    entries
+}
+
+pub fn count_pending(peer: &str) -> usize {
+    lock_outbox().get(peer).map_or(0, BTreeMap::len)
+}
+
+/// Remove the `sent` entries from the queue of `peer`, after the peer
+/// confirmed receiving them, unless they were changed again in the meantime
+#[allow(clippy::significant_drop_tightening)] // the outbox is written while it is locked
+pub fn acknowledge(peer: &str, sent: &[UnboxedEntry]) -> anyhow::Result<()> {
+    let mut outbox = lock_outbox();
+
+    if let Some(queue) = outbox.get_mut(peer) {
+        for entry in sent {
+            if queue.get(&entry.number).is_some_and(|queued| queued.timestamp <= entry.timestamp) {
+                queue.remove(&entry.number);
+            }
+        }
+
+        if queue.is_empty() {
+            outbox.remove(peer);
+        }
+    }
+
+    write_outbox(&outbox)
+}
+
+/// Forget everything that still had to be sent to `peer`
+#[allow(clippy::significant_drop_tightening)] // the outbox is written while it is locked
+pub fn clear(peer: &str) -> anyhow::Result<()> {
+    let mut outbox = lock_outbox();
+
+    if outbox.remove(peer).is_some() {
+        write_outbox(&outbox)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{same_entry, test_entry};
+
+    #[test]
+    fn reads_what_is_written() {
+        let mut outbox = Outbox::new();
+        insert_newer(outbox.entry(String::from("a:1")).or_default(), vec![test_entry(123, 5)]);
+
+        // NOTE: `write_outbox` writes with `serde_json::to_writer`
+        let read = parse_outbox(&serde_json::to_vec(&outbox).unwrap()).unwrap();
+
+        assert_eq!(read.len(), 1);
+        assert_eq!(read["a:1"].len(), 1);
+        assert!(same_entry(&read["a:1"][&123], &test_entry(123, 5)));
+    }
+
+    #[test]
+    fn reads_lists_keeping_the_newest_entries() {
+        let lists: HashMap<&str, Entries> =
+            vec![("a:1", vec![test_entry(123, 5), test_entry(123, 4), test_entry(456, 1)])]
+                .into_iter()
+                .collect();
+
+        let read = parse_outbox(&serde_json::to_vec(&lists).unwrap()).unwrap();
+
+        assert_eq!(read["a:1"].len(), 2);
+        assert_eq!(read["a:1"][&123].timestamp, 5);
+        assert_eq!(read["a:1"][&456].timestamp, 1);
+    }
+
+    #[test]
+    fn rejects_other_data() {
+        assert!(parse_outbox(b"{\"a:1\": 3}").is_err());
+    }
+}
@@ -0,0 +1,389 @@
+//! The servers we sync our entries with, stored in `DB_PEERS_PATH`.
+//!
+//! When there is no stored list yet, it is created from `SERVERS`, which is
+//! ignored afterwards.
+//!
+//! Peers are identified by their configured `<hostname>:<port>`, which is
+//! resolved again whenever we connect to them, since the addresses of some
+//! servers change regularly.
+
+use super::{outbox, replication, write_json_file};
+use crate::{config::resolve_server, get_current_itelex_timestamp, CONFIG};
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    fs,
+    net::SocketAddr,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Peer {
+    /// `<hostname>:<port>` of the peer
+    pub host: String,
+    /// The address `host` resolved to the last time
+    #[serde(default)]
+    pub address: Option<SocketAddr>,
+    /// Disabled peers are neither sent updates nor queried
+    pub disabled: bool,
+}
+
+/// How peers are stored. Before peers were identified by their host, they
+/// were stored by their address, which is still read
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum StoredPeer {
+    Peer(Peer),
+    Address { address: SocketAddr, disabled: bool },
+}
+
+impl From<StoredPeer> for Peer {
+    fn from(stored: StoredPeer) -> Self {
+        match stored {
+            StoredPeer::Peer(peer) => peer,
+            // NOTE: the outbox and the replication state of these peers are
+            // stored by their address, which thereby stays their key
+            StoredPeer::Address { address, disabled } => {
+                Self { host: address.to_string(), address: Some(address), disabled }
+            }
+        }
+    }
+}
+
+/// The circuit breaker of a peer
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    #[default]
+    Closed,
+    /// The peer failed `SERVER_CIRCUIT_BREAKER_THRESHOLD` times in a row and is
+    /// left alone until `retry_at`
+    Open,
+    /// The peer is being tried again after its circuit was open
+    HalfOpen,
+}
+
+/// How syncing with a peer went since the server was started
+#[derive(serde::Serialize, Debug, Clone, Default)]
+pub struct PeerHealth {
+    /// The itelex timestamp of when we last sent the peer our changes
+    pub last_push: Option<u32>,
+    /// The number of failed updates or queries since the last successful one
+    pub consecutive_failures: u32,
+    /// The itelex timestamp of the first of the `consecutive_failures`
+    pub failing_since: Option<u32>,
+    pub last_failure: Option<u32>,
+    pub last_error: Option<String>,
+    pub circuit: CircuitState,
+    /// The itelex timestamp after which the peer is tried again
+    pub retry_at: Option<u32>,
+}
+
+impl PeerHealth {
+    fn record_success(&mut self, host: &str) {
+        if self.circuit != CircuitState::Closed {
+            info!("syncing with peer {} works again", host);
+        }
+
+        self.consecutive_failures = 0;
+        self.failing_since = None;
+        self.circuit = CircuitState::Closed;
+        self.retry_at = None;
+    }
+
+    fn record_push(&mut self, host: &str) {
+        self.last_push = Some(get_current_itelex_timestamp());
+        self.record_success(host);
+    }
+
+    fn record_failure(&mut self, host: &str, error: &anyhow::Error) -> Duration {
+        let now = get_current_itelex_timestamp();
+
+        self.consecutive_failures += 1;
+        self.failing_since.get_or_insert(now);
+        self.last_failure = Some(now);
+        self.last_error = Some(format!("{:#}", error));
+
+        let delay = backoff(self.consecutive_failures);
+
+        if self.consecutive_failures >= config!(SERVER_CIRCUIT_BREAKER_THRESHOLD)
+            && self.circuit != CircuitState::Open
+        {
+            warn!(
+                "peer {} failed {} times in a row, pausing syncing with it",
+                host, self.consecutive_failures
+            );
+
+            self.circuit = CircuitState::Open;
+        }
+
+        #[allow(clippy::cast_possible_truncation)]
+        let retry_at = now.saturating_add(delay.as_secs() as u32);
+
+        self.retry_at = Some(retry_at);
+
+        delay
+    }
+
+    fn should_attempt(&mut self) -> bool {
+        if self.circuit != CircuitState::Open {
+            return true;
+        }
+
+        if self.retry_at.is_none_or(|retry_at| get_current_itelex_timestamp() >= retry_at) {
+            self.circuit = CircuitState::HalfOpen;
+
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// A peer and the state of syncing with it
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct PeerStatus {
+    #[serde(flatten)]
+    pub peer: Peer,
+    #[serde(flatten)]
+    pub health: PeerHealth,
+    /// The itelex timestamp of when we last completed a full query of the peer
+    pub last_full_query: Option<u32>,
+    /// The highest timestamp of an entry we received from the peer
+    pub received: Option<u32>,
+    /// The number of entries waiting to be sent to the peer
+    pub queued: usize,
+}
+
+static PEERS: Lazy<Mutex<Vec<Peer>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// The health of the peers by their host
+static HEALTH: Lazy<Mutex<HashMap<String, PeerHealth>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn lock_peers() -> MutexGuard<'static, Vec<Peer>> {
+    PEERS.lock().expect("the peer list lock was poisoned")
+}
+
+fn lock_health() -> MutexGuard<'static, HashMap<String, PeerHealth>> {
+    HEALTH.lock().expect("the peer health lock was poisoned")
+}
+
+pub fn load_peers() -> anyhow::Result<()> {
+    let peers_path = Path::new(&config!(DB_PEERS_PATH));
+
+    let peers: Vec<Peer> = if peers_path.exists() {
+        let stored: Vec<StoredPeer> = serde_json::from_slice(&fs::read(peers_path)?)
+            .context("Failed to read the peer list")?;
+
+        let migrate = stored.iter().any(|peer| matches!(peer, StoredPeer::Address { .. }));
+
+        let peers: Vec<Peer> = stored.into_iter().map(Peer::from).collect();
+
+        if migrate {
+            info!("Migrating the peer list to identify peers by their host");
+
+            write_peers(&peers)?;
+        }
+
+        peers
+    } else {
+        info!("Creating the peer list from SERVERS");
+
+        config!(SERVERS)
+            .iter()
+            .map(|host| Peer { host: host.clone(), address: None, disabled: false })
+            .collect()
+    };
+
+    info!("Syncing with {} peers", peers.iter().filter(|peer| !peer.disabled).count());
+
+    *lock_peers() = peers;
+
+    Ok(())
+}
+
+fn write_peers(peers: &[Peer]) -> anyhow::Result<()> {
+    write_json_file(&config!(DB_PEERS_PATH), &peers).context("Failed to write the peer list")
+}
+
+pub fn list_peers() -> Vec<Peer> {
+    lock_peers().clone()
+}
+
+pub fn peer_statuses() -> Vec<PeerStatus> {
+    let health = lock_health().clone();
+
+    list_peers()
+        .into_iter()
+        .map(|peer| {
+            let state = replication::peer_state(&peer.host);
+
+            PeerStatus {
+                health: health.get(&peer.host).cloned().unwrap_or_default(),
+                last_full_query: state.last_full_query,
+                received: state.received,
+                queued: outbox::count_pending(&peer.host),
+                peer,
+            }
+        })
+        .collect()
+}
+
+/// Record that we successfully sent our changes to the peer at `host`
+pub fn record_push(host: &str) {
+    lock_health().entry(host.to_owned()).or_default().record_push(host);
+}
+
+/// Record that we successfully queried the peer at `host`
+pub fn record_query(host: &str) {
+    lock_health().entry(host.to_owned()).or_default().record_success(host);
+}
+
+/// How long to wait after the `failures`th failure in a row: `SERVER_COOLDOWN`
+/// doubled for every previous failure up to `SERVER_COOLDOWN_MAX`, of which a
+/// random half is left out, so that retries of several servers don't line up
+fn backoff(failures: u32) -> Duration {
+    let max = config!(SERVER_COOLDOWN_MAX);
+
+    let delay = config!(SERVER_COOLDOWN)
+        .checked_mul(1 << failures.saturating_sub(1).min(31))
+        .map_or(max, |delay| delay.min(max));
+
+    delay / 2 + delay.mul_f64(rand::thread_rng().gen_range(0.0, 0.5))
+}
+
+/// Record that updating or querying the peer at `host` failed with `error`,
+/// returning how long to wait before trying again
+pub fn record_failure(host: &str, error: &anyhow::Error) -> Duration {
+    lock_health().entry(host.to_owned()).or_default().record_failure(host, error)
+}
+
+/// Whether the peer at `host` may be contacted, which is not the case while
+/// its circuit is open and its retry time has not come yet
+pub fn should_attempt(host: &str) -> bool {
+    lock_health().get_mut(host).is_none_or(PeerHealth::should_attempt)
+}
+
+/// How long syncing with the peer at `host` is paused for, because its circuit
+/// is open
+pub fn paused_for(host: &str) -> Duration {
+    let now = get_current_itelex_timestamp();
+
+    match lock_health().get(host) {
+        Some(PeerHealth { circuit: CircuitState::Open, retry_at: Some(retry_at), .. }) => {
+            Duration::from_secs(u64::from(retry_at.saturating_sub(now)))
+        }
+        _ => Duration::from_secs(0),
+    }
+}
+
+/// Whether the peer at `host` has been failing for longer than
+/// `SERVER_GIVE_UP_AFTER`, so that sending it the updates queued for it is
+/// not retried until the next reconciliation
+pub fn should_give_up(host: &str) -> bool {
+    let now = get_current_itelex_timestamp();
+
+    lock_health().get(host).and_then(|health| health.failing_since).is_some_and(|since| {
+        u64::from(now.saturating_sub(since)) > config!(SERVER_GIVE_UP_AFTER).as_secs()
+    })
+}
+
+/// The last known addresses of all peers, including disabled ones
+pub fn peer_addresses() -> Vec<(String, SocketAddr)> {
+    lock_peers()
+        .iter()
+        .filter_map(|peer| peer.address.map(|address| (peer.host.clone(), address)))
+        .collect()
+}
+
+/// The hosts of the peers we sync with
+pub fn active_peers() -> Vec<String> {
+    lock_peers().iter().filter(|peer| !peer.disabled).map(|peer| peer.host.clone()).collect()
+}
+
+/// Look up the current address of the peer at `host` and remember it
+#[allow(clippy::significant_drop_tightening)] // the peers are written while they are locked
+pub async fn resolve_peer(host: &str) -> anyhow::Result<SocketAddr> {
+    let address = resolve_server(host)
+        .await
+        .with_context(|| format!("Failed to resolve peer {}", host))?
+        .ok_or_else(|| anyhow!("peer {} has no address", host))?;
+
+    let mut peers = lock_peers();
+
+    if let Some(peer) = peers.iter_mut().find(|peer| peer.host == host) {
+        if peer.address == Some(address) {
+            return Ok(address);
+        }
+
+        match peer.address {
+            Some(old_address) => {
+                warn!("The address of peer {} changed from {} to {}", host, old_address, address)
+            }
+            None => info!("peer {} is at {}", host, address),
+        }
+
+        peer.address = Some(address);
+
+        write_peers(&peers)?;
+    }
+
+    Ok(address)
+}
+
+#[allow(clippy::significant_drop_tightening)] // the peers are written while they are locked
+pub fn add_peer(host: String, address: SocketAddr) -> anyhow::Result<()> {
+    let mut peers = lock_peers();
+
+    if peers.iter().any(|peer| peer.host == host) {
+        bail!(anyhow!("{} already is a peer", host));
+    }
+
+    info!("adding peer {} at {}", host, address);
+
+    peers.push(Peer { host, address: Some(address), disabled: false });
+
+    write_peers(&peers)
+}
+
+/// Remove the peer at `host` and everything that was still to be sent to it
+pub fn remove_peer(host: &str) -> anyhow::Result<()> {
+    let mut peers = lock_peers();
+
+    let index = peers
+        .iter()
+        .position(|peer| peer.host == host)
+        .ok_or_else(|| anyhow!("{} is not a peer", host))?;
+
+    info!("removing peer {}", host);
+
+    peers.remove(index);
+
+    write_peers(&peers)?;
+
+    drop(peers);
+
+    lock_health().remove(host);
+
+    outbox::clear(host)
+}
+
+#[allow(clippy::significant_drop_tightening)] // the peers are written while they are locked
+pub fn set_peer_disabled(host: &str, disabled: bool) -> anyhow::Result<()> {
+    let mut peers = lock_peers();
+
+    let peer = peers
+        .iter_mut()
+        .find(|peer| peer.host == host)
+        .ok_or_else(|| anyhow!("{} is not a peer", host))?;
+
+    info!("{} peer {}", if disabled { "disabling" } else { "enabling" }, host);
+
+    peer.disabled = disabled;
+
+    write_peers(&peers)
+}
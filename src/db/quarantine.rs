@@ -0,0 +1,160 @@
+//! Entries from peers whose timestamps are too far in the future to be
+//! trusted, stored in `DB_QUARANTINE_PATH` until an admin accepts or discards
+//! them.
+//!
+//! Such an entry would otherwise be newer than every correct update of it, so
+//! that the entry could never be changed again.
+//!
+//! Entries an admin accepted or discarded are kept as resolved, so that the
+//! copies the peer sends again are not quarantined again, until their
+//! timestamps are no longer too far in the future.
+
+use super::{get_entry_by_number, update_entry, write_json_file, ChangeSource};
+use crate::{get_current_itelex_timestamp, UnboxedEntry, CONFIG};
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use std::{
+    fs,
+    net::SocketAddr,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct QuarantinedEntry {
+    pub id: u64,
+    /// The itelex timestamp of when the entry was received
+    pub time: u32,
+    pub peer: SocketAddr,
+    pub entry: UnboxedEntry,
+    /// Whether an admin already accepted or discarded the entry
+    #[serde(default)]
+    pub resolved: bool,
+}
+
+impl QuarantinedEntry {
+    /// Whether `entry` is the version of the entry that was quarantined
+    const fn covers(&self, entry: &UnboxedEntry) -> bool {
+        self.entry.number == entry.number && self.entry.timestamp == entry.timestamp
+    }
+}
+
+static QUARANTINE: Lazy<Mutex<Vec<QuarantinedEntry>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+fn lock_quarantine() -> MutexGuard<'static, Vec<QuarantinedEntry>> {
+    QUARANTINE.lock().expect("the quarantine lock was poisoned")
+}
+
+pub fn load_quarantine() -> anyhow::Result<()> {
+    let quarantine_path = Path::new(&config!(DB_QUARANTINE_PATH));
+
+    if !quarantine_path.exists() {
+        return Ok(());
+    }
+
+    let quarantine: Vec<QuarantinedEntry> = serde_json::from_slice(&fs::read(quarantine_path)?)
+        .context("Failed to read the quarantine")?;
+
+    let n_unresolved = quarantine.iter().filter(|quarantined| !quarantined.resolved).count();
+
+    if n_unresolved > 0 {
+        warn!("There are {} quarantined entries", n_unresolved);
+    }
+
+    *lock_quarantine() = quarantine;
+
+    Ok(())
+}
+
+fn write_quarantine(quarantine: &[QuarantinedEntry]) -> anyhow::Result<()> {
+    write_json_file(&config!(DB_QUARANTINE_PATH), &quarantine)
+        .context("Failed to write the quarantine")
+}
+
+/// Whether `entry` is further in the future than `SYNC_QUARANTINE_SKEW` allows
+pub fn is_too_far_in_future(entry: &UnboxedEntry) -> bool {
+    let now = get_current_itelex_timestamp();
+
+    u64::from(entry.timestamp.saturating_sub(now)) > config!(SYNC_QUARANTINE_SKEW).as_secs()
+}
+
+/// Hold back `entry`, which `peer` sent us
+#[allow(clippy::significant_drop_tightening)] // the quarantine is written while it is locked
+pub fn quarantine_entry(entry: UnboxedEntry, peer: SocketAddr) -> anyhow::Result<()> {
+    let mut quarantine = lock_quarantine();
+
+    // NOTE: peers resend their entries on every full query
+    if quarantine.iter().any(|quarantined| quarantined.covers(&entry)) {
+        return Ok(());
+    }
+
+    // resolved entries that are no longer too far in the future would not be
+    // quarantined anyway
+    quarantine.retain(|quarantined| {
+        !quarantined.resolved || is_too_far_in_future(&quarantined.entry)
+    });
+
+    warn!(
+        "Quarantining entry {} from {}, its timestamp is {} seconds in the future",
+        entry.number,
+        peer,
+        entry.timestamp.saturating_sub(get_current_itelex_timestamp())
+    );
+
+    let id = quarantine.iter().map(|quarantined| quarantined.id + 1).max().unwrap_or(0);
+
+    quarantine.push(QuarantinedEntry {
+        id,
+        time: get_current_itelex_timestamp(),
+        peer,
+        entry,
+        resolved: false,
+    });
+
+    write_quarantine(&quarantine)
+}
+
+/// All quarantined entries that are not resolved yet, oldest first
+pub fn get_quarantined_entries() -> Vec<QuarantinedEntry> {
+    lock_quarantine().iter().filter(|quarantined| !quarantined.resolved).cloned().collect()
+}
+
+#[allow(clippy::significant_drop_tightening)] // the quarantine is written while it is locked
+fn resolve_quarantined_entry(id: u64) -> anyhow::Result<()> {
+    let mut quarantine = lock_quarantine();
+
+    quarantine
+        .iter_mut()
+        .find(|quarantined| quarantined.id == id && !quarantined.resolved)
+        .ok_or_else(|| anyhow!("there is no quarantined entry with id {}", id))?
+        .resolved = true;
+
+    write_quarantine(&quarantine)
+}
+
+/// Write the quarantined entry with `id` into the database with the current
+/// timestamp instead of its own
+pub fn accept_quarantined_entry(id: u64, source: ChangeSource) -> anyhow::Result<()> {
+    let mut entry = lock_quarantine()
+        .iter()
+        .find(|quarantined| quarantined.id == id && !quarantined.resolved)
+        .map(|quarantined| quarantined.entry.clone())
+        .ok_or_else(|| anyhow!("there is no quarantined entry with id {}", id))?;
+
+    entry.pin = get_entry_by_number(entry.number)?.map_or(entry.pin, |current| current.pin);
+    entry.timestamp = get_current_itelex_timestamp();
+
+    info!("accepting quarantined entry {}", id);
+
+    update_entry(Box::new(entry), source)?;
+
+    resolve_quarantined_entry(id)
+}
+
+pub fn discard_quarantined_entry(id: u64) -> anyhow::Result<()> {
+    resolve_quarantined_entry(id)?;
+
+    info!("discarded quarantined entry {}", id);
+
+    Ok(())
+}
@@ -0,0 +1,144 @@
+//! What we know about the state of every peer server's copy of the directory,
+//! stored in `DB_REPLICATION_PATH`.
+//!
+//! The watermarks are the highest timestamps exchanged with a peer, so that
+//! only entries newer than them have to be exchanged again.
+//!
+//! Peers send us their own changes as they happen, so they are only queried
+//! fully as a fallback: when we have not received anything from them yet, when
+//! their watermark can't be trusted or when the last full query is older than
+//! `FULL_QUERY_MAX_AGE`.
+
+use super::{get_all_entries, peers, write_json_file};
+use crate::{get_current_itelex_timestamp, Entries, UnboxedEntry, CONFIG};
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    fs,
+    net::SocketAddr,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct PeerState {
+    /// The highest timestamp of an entry we received from the peer, leaving
+    /// out ones that were held back for being too far in the future
+    pub received: Option<u32>,
+    /// The highest timestamp of an entry the peer confirmed receiving from us
+    pub confirmed: Option<u32>,
+    /// When we last completed a full query of the peer
+    pub last_full_query: Option<u32>,
+}
+
+/// The states by the host of their peer
+type States = HashMap<String, PeerState>;
+
+static STATES: Lazy<Mutex<States>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn lock_states() -> MutexGuard<'static, States> {
+    STATES.lock().expect("the replication state lock was poisoned")
+}
+
+pub fn load_replication_state() -> anyhow::Result<()> {
+    let state_path = Path::new(&config!(DB_REPLICATION_PATH));
+
+    if !state_path.exists() {
+        return Ok(());
+    }
+
+    *lock_states() = serde_json::from_slice(&fs::read(state_path)?)
+        .context("Failed to read the replication state")?;
+
+    Ok(())
+}
+
+pub fn write_replication_state() -> anyhow::Result<()> {
+    let states = lock_states().clone();
+
+    write_json_file(&config!(DB_REPLICATION_PATH), &states)
+        .context("Failed to write the replication state")
+}
+
+/// The host of the configured peer `address` belongs to.
+///
+/// Peers connect to us from an arbitrary port, so they are matched by their ip
+/// address if the port differs.
+pub fn peer_for_address(address: SocketAddr) -> Option<String> {
+    let servers = peers::peer_addresses();
+
+    servers
+        .iter()
+        .find(|(_, server)| *server == address)
+        .or_else(|| servers.iter().find(|(_, server)| server.ip() == address.ip()))
+        .map(|(host, _)| host.clone())
+}
+
+pub fn peer_state(peer: &str) -> PeerState {
+    lock_states().get(peer).cloned().unwrap_or_default()
+}
+
+fn raise(watermark: &mut Option<u32>, timestamp: u32) {
+    *watermark = Some(watermark.map_or(timestamp, |watermark| watermark.max(timestamp)));
+}
+
+/// Record that the peer at `address` sent us an entry with `timestamp`.
+///
+/// This is only kept in memory until the next `write_replication_state`, since
+/// it happens for every entry of a full query.
+pub fn record_received(address: SocketAddr, timestamp: u32) {
+    if let Some(peer) = peer_for_address(address) {
+        raise(&mut lock_states().entry(peer).or_default().received, timestamp);
+    }
+}
+
+/// Record that `peer` confirmed receiving `entries`
+pub fn record_confirmed(peer: &str, entries: &[UnboxedEntry]) -> anyhow::Result<()> {
+    if let Some(timestamp) = entries.iter().map(|entry| entry.timestamp).max() {
+        raise(&mut lock_states().entry(peer.to_owned()).or_default().confirmed, timestamp);
+
+        write_replication_state()?;
+    }
+
+    Ok(())
+}
+
+pub fn record_full_query(peer: &str) -> anyhow::Result<()> {
+    lock_states().entry(peer.to_owned()).or_default().last_full_query =
+        Some(get_current_itelex_timestamp());
+
+    write_replication_state()
+}
+
+/// Whether the watermarks of `peer` can't be used and the full directory has
+/// to be queried instead
+pub fn needs_full_query(peer: &str) -> bool {
+    let state = peer_state(peer);
+    let now = get_current_itelex_timestamp();
+
+    // NOTE: entries further in the future are quarantined and don't raise the
+    // watermark, so a watermark beyond that means that our clock went back
+    let watermark_suspect = state.received.is_none_or(|received| {
+        u64::from(received.saturating_sub(now)) > config!(SYNC_QUARANTINE_SKEW).as_secs()
+    });
+
+    let full_query_outdated = state.last_full_query.is_none_or(|last_full_query| {
+        u64::from(now.saturating_sub(last_full_query)) > config!(FULL_QUERY_MAX_AGE).as_secs()
+    });
+
+    watermark_suspect || full_query_outdated
+}
+
+/// Our entries that are newer than what `peer` confirmed receiving from us, or
+/// `None` if it never confirmed anything
+pub fn entries_since_confirmed(peer: &str) -> anyhow::Result<Option<Entries>> {
+    let confirmed = match peer_state(peer).confirmed {
+        Some(confirmed) => confirmed,
+        None => return Ok(None),
+    };
+
+    Ok(Some(
+        get_all_entries()?.into_iter().filter(|entry| entry.timestamp > confirmed).collect(),
+    ))
+}
@@ -0,0 +1,157 @@
+//! Matching names against search patterns, shared by `PeerSearch`, the ASCII
+//! protocol and the web interface.
+//!
+//! Names and patterns are compared in a normalized form, so that "mueller"
+//! finds "Müller" and "berlin" finds "Berlin".
+
+use crate::{Entries, CONFIG};
+use std::cmp::Reverse;
+
+/// How many letters the longest word of a public search has to have, so that
+/// the directory can't be listed with a single search
+///
+/// NOTE: the help of the ASCII protocol states this too
+pub const MIN_PUBLIC_WORD_LENGTH: usize = 3;
+
+/// Fold `text` to lower case, spell out umlauts and `ß` as their two letter
+/// forms and replace punctuation with spaces
+pub fn normalize(text: &str) -> String {
+    let mut normalized = String::with_capacity(text.len());
+
+    for character in text.chars().flat_map(char::to_lowercase) {
+        match character {
+            'ä' => normalized.push_str("ae"),
+            'ö' => normalized.push_str("oe"),
+            'ü' => normalized.push_str("ue"),
+            'ß' => normalized.push_str("ss"),
+            // a decomposed umlaut, like "u\u{308}"
+            '\u{308}' if normalized.ends_with(&['a', 'o', 'u'][..]) => normalized.push('e'),
+            // other combining marks are left out, like the accents of precomposed letters
+            '\u{300}'..='\u{36f}' => {}
+            character if character.is_alphanumeric() => normalized.push(character),
+            _ => normalized.push(' '),
+        }
+    }
+
+    normalized
+}
+
+/// The normalized words of `text`
+pub fn tokens(text: &str) -> Vec<String> {
+    normalize(text).split_whitespace().map(String::from).collect()
+}
+
+/// Whether `pattern` is specific enough for a public search
+pub fn is_public_pattern(pattern: &str) -> bool {
+    tokens(pattern).iter().any(|word| word.chars().count() >= MIN_PUBLIC_WORD_LENGTH)
+}
+
+/// How well `name` matches the normalized pattern `words`, higher being
+/// better, or `None` if it does not contain every word
+pub fn match_quality(words: &[String], name: &str) -> Option<u32> {
+    let name = normalize(name);
+    let name_tokens: Vec<&str> = name.split_whitespace().collect();
+
+    let mut quality = 0;
+
+    for word in words {
+        if !name.contains(word.as_str()) {
+            return None;
+        }
+
+        quality += if name_tokens.iter().any(|token| token == word) {
+            3
+        } else if name_tokens.iter().any(|token| token.starts_with(word.as_str())) {
+            2
+        } else {
+            1
+        };
+    }
+
+    Some(quality)
+}
+
+/// The `entries` whose names match `pattern`, the best matches first if
+/// `SEARCH_RANKING` is enabled
+pub fn search_entries(entries: Entries, pattern: &str) -> Entries {
+    search(entries, pattern, |entry| entry.name.0.as_str(), config!(SEARCH_RANKING))
+}
+
+fn search<T>(items: Vec<T>, pattern: &str, name: impl Fn(&T) -> &str, ranking: bool) -> Vec<T> {
+    let words = tokens(pattern);
+
+    let mut found: Vec<_> = items
+        .into_iter()
+        .filter_map(|item| match_quality(&words, name(&item)).map(|quality| (quality, item)))
+        .collect();
+
+    if ranking {
+        // NOTE: the sort is stable, so equally good matches keep their order
+        found.sort_by_key(|(quality, _)| Reverse(*quality));
+    }
+
+    found.into_iter().map(|(_, item)| item).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spells_out_umlauts() {
+        assert_eq!(normalize("Müller"), "mueller");
+        assert_eq!(normalize("MÜLLER"), "mueller");
+        assert_eq!(normalize("Mu\u{308}ller"), "mueller");
+        assert_eq!(normalize("Ärger Öl"), "aerger oel");
+    }
+
+    #[test]
+    fn spells_out_sharp_s() {
+        assert_eq!(normalize("Straße"), "strasse");
+    }
+
+    #[test]
+    fn leaves_out_other_accents() {
+        assert_eq!(normalize("Cafe\u{301}"), "cafe");
+    }
+
+    #[test]
+    fn splits_words_at_punctuation() {
+        assert_eq!(tokens("Müller-Lüdenscheidt, K."), ["mueller", "luedenscheidt", "k"]);
+        assert_eq!(tokens("Meier/Schmidt GmbH & Co.KG"), ["meier", "schmidt", "gmbh", "co", "kg"]);
+    }
+
+    #[test]
+    fn matches_spelled_out_forms() {
+        assert!(match_quality(&tokens("mueller"), "Müller").is_some());
+        assert!(match_quality(&tokens("Müller"), "Mueller").is_some());
+        assert!(match_quality(&tokens("strasse"), "Hauptstraße 1").is_some());
+        assert!(match_quality(&tokens("straße"), "Hauptstrasse 1").is_some());
+        assert!(match_quality(&tokens("schmidt meier"), "Schmidt-Müller").is_none());
+    }
+
+    #[test]
+    fn rejects_short_public_patterns() {
+        assert!(!is_public_pattern(""));
+        assert!(!is_public_pattern("  "));
+        assert!(!is_public_pattern("a b"));
+        assert!(!is_public_pattern("e."));
+        assert!(is_public_pattern("Jo Mey"));
+        assert!(is_public_pattern("Öl"));
+    }
+
+    #[test]
+    fn ranks_exact_matches_before_prefixes_before_substrings() {
+        let names = vec!["Goldschmidt", "Hans Schmidtke", "Meier", "Anna Schmidt", "Schmidt"];
+
+        assert_eq!(
+            search(names.clone(), "schmidt", |name| *name, true),
+            ["Anna Schmidt", "Schmidt", "Hans Schmidtke", "Goldschmidt"]
+        );
+
+        assert_eq!(
+            search(names, "schmidt", |name| *name, false),
+            ["Goldschmidt", "Hans Schmidtke", "Anna Schmidt", "Schmidt"]
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! Dated copies of the database, which are written every time the database is
+//! synced to disk and can be restored into the live database.
+
+use super::{format, get_entry_by_number, same_entry, storage, update_entry, ChangeSource};
+use crate::{get_current_itelex_timestamp, CONFIG};
+use anyhow::Context;
+use std::{
+    cmp::Reverse,
+    fs::{self, File},
+    io::BufWriter,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const PREFIX: &str = "snapshot-";
+const SUFFIX: &str = ".db";
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct SnapshotInfo {
+    /// The unix timestamp the snapshot was taken at
+    pub id: u64,
+    pub size: u64,
+}
+
+fn snapshot_path(id: u64) -> PathBuf {
+    PathBuf::from(&config!(DB_SNAPSHOT_DIR)).join(format!("{}{}{}", PREFIX, id, SUFFIX))
+}
+
+fn id_from_file_name(file_name: &str) -> Option<u64> {
+    file_name.strip_prefix(PREFIX)?.strip_suffix(SUFFIX)?.parse().ok()
+}
+
+/// List all snapshots, newest first
+pub fn list_snapshots() -> anyhow::Result<Vec<SnapshotInfo>> {
+    let mut snapshots = Vec::new();
+
+    fs::create_dir_all(&config!(DB_SNAPSHOT_DIR))?;
+
+    for dir_entry in fs::read_dir(&config!(DB_SNAPSHOT_DIR))? {
+        let dir_entry = dir_entry?;
+
+        if let Some(id) = dir_entry.file_name().to_str().and_then(id_from_file_name) {
+            snapshots.push(SnapshotInfo { id, size: dir_entry.metadata()?.len() });
+        }
+    }
+
+    snapshots.sort_by_key(|snapshot| Reverse(snapshot.id));
+
+    Ok(snapshots)
+}
+
+/// Write all entries to a new snapshot and delete the ones that are no longer
+/// needed
+pub fn create_snapshot() -> anyhow::Result<u64> {
+    let id = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    fs::create_dir_all(&config!(DB_SNAPSHOT_DIR))?;
+
+    let snapshot_file: File = File::create(snapshot_path(id))?;
+
+    let mut writer = format::DbWriter::new(BufWriter::new(snapshot_file))?;
+
+    for entry in storage().entries()? {
+        writer.write(&entry)?;
+    }
+
+    writer.finish()?.into_inner()?.sync_all()?;
+
+    debug!("created snapshot {}", id);
+
+    prune_snapshots(id)?;
+
+    Ok(id)
+}
+
+/// Keep at most `DB_SNAPSHOT_COUNT` snapshots and none that are older than
+/// `DB_SNAPSHOT_MAX_AGE`, except for the newest one
+fn prune_snapshots(now: u64) -> anyhow::Result<()> {
+    let max_age: Duration = config!(DB_SNAPSHOT_MAX_AGE);
+
+    for (i, snapshot) in list_snapshots()?.into_iter().enumerate() {
+        let too_many = i >= config!(DB_SNAPSHOT_COUNT).max(1);
+        let too_old = i > 0 && now.saturating_sub(snapshot.id) > max_age.as_secs();
+
+        if too_many || too_old {
+            debug!("removing snapshot {}", snapshot.id);
+
+            fs::remove_file(snapshot_path(snapshot.id))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write all entries of a snapshot that differ from the live database back
+/// into it, returning how many entries were restored.
+///
+/// The restored entries get the current timestamp, so that they overwrite the
+/// entries of the other servers, that caused the snapshot to be restored.
+/// Entries that were created after the snapshot was taken are kept.
+pub fn restore_snapshot(id: u64) -> anyhow::Result<usize> {
+    if config!(SERVER_PIN) == 0 {
+        bail!(anyhow!("Refused to restore a snapshot without a SERVER_PIN"));
+    }
+
+    let path = snapshot_path(id);
+
+    let buffer = fs::read(&path).with_context(|| format!("Failed to read snapshot {}", id))?;
+
+    let entries = format::read_entries(&buffer, false)?;
+
+    warn!("Restoring snapshot {} with {} entries", id, entries.len());
+
+    let mut n_restored = 0;
+
+    for mut entry in entries {
+        if let Some(current) = get_entry_by_number(entry.number)? {
+            entry.timestamp = current.timestamp;
+
+            if same_entry(&current, &entry) {
+                continue;
+            }
+        }
+
+        entry.timestamp = get_current_itelex_timestamp();
+
+        update_entry(Box::new(entry), ChangeSource::Server)?;
+
+        n_restored += 1;
+    }
+
+    warn!("Restored {} entries from snapshot {}", n_restored, id);
+
+    Ok(n_restored)
+}
@@ -0,0 +1,191 @@
+use super::{deserialize_entry, serialize_entry, Storage, RECORD_SIZE};
+use crate::{UnboxedEntry, CONFIG};
+use anyhow::Context;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+// NOTE: besides the serialized package, which is what we read entries from,
+// all fields are stored in their own columns, so that the database can be
+// queried with ordinary tools
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS entries (
+    number INTEGER PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    client_type INTEGER NOT NULL,
+    hostname TEXT,
+    ipaddress TEXT,
+    port INTEGER NOT NULL,
+    extension INTEGER NOT NULL,
+    pin INTEGER NOT NULL,
+    disabled INTEGER NOT NULL,
+    timestamp INTEGER NOT NULL,
+    package BLOB NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS changed (
+    number INTEGER PRIMARY KEY NOT NULL
+);
+";
+
+/// Stores all entries in the SQLite database at `DB_SQLITE_PATH`.
+///
+/// Every write is stored immediately, so persisting is a no-op.
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let connection = if config!(SERVER_PIN) == 0 {
+            // We never write to disk without a server pin (see `sync_db_to_disk`),
+            // so we work on an in memory copy of the database
+            let connection = Connection::open_in_memory()?;
+
+            connection.execute_batch(SCHEMA)?;
+
+            if Path::new(path).exists() {
+                connection.execute("ATTACH DATABASE ?1 AS disk", params![path])?;
+                connection.execute_batch(
+                    "INSERT INTO entries SELECT * FROM disk.entries; DETACH DATABASE disk;",
+                )?;
+            }
+
+            connection
+        } else {
+            let connection = Connection::open(path)?;
+
+            connection.execute_batch(SCHEMA)?;
+
+            connection
+        };
+
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().expect("the database connection lock was poisoned")
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load(&self) -> anyhow::Result<()> {
+        let n_entries: i64 =
+            self.connection().query_row("SELECT COUNT(*) FROM entries", params![], |row| {
+                row.get(0)
+            })?;
+
+        info!("The database contains {} entries", n_entries);
+
+        Ok(())
+    }
+
+    fn persist(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn get(&self, number: u32) -> anyhow::Result<Option<UnboxedEntry>> {
+        let package: Option<Vec<u8>> = self
+            .connection()
+            .query_row("SELECT package FROM entries WHERE number = ?1", params![number], |row| {
+                row.get(0)
+            })
+            .optional()?;
+
+        package.as_deref().map(deserialize_entry).transpose()
+    }
+
+    fn insert(&self, entry: UnboxedEntry) -> anyhow::Result<()> {
+        let mut package = Vec::with_capacity(RECORD_SIZE);
+        serialize_entry(&entry, &mut package)?;
+
+        self.connection()
+            .execute(
+                "INSERT OR REPLACE INTO entries (number, name, client_type, hostname, ipaddress, \
+                 port, extension, pin, disabled, timestamp, package) VALUES (?1, ?2, ?3, ?4, ?5, \
+                 ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    entry.number,
+                    entry.name.0.to_string(),
+                    entry.client_type as u8,
+                    entry.hostname().map(String::from),
+                    entry.ipaddress().map(|ipaddress| ipaddress.to_string()),
+                    entry.port,
+                    entry.extension,
+                    entry.pin,
+                    entry.disabled(),
+                    entry.timestamp,
+                    package,
+                ],
+            )
+            .context("Failed to write entry to the database")?;
+
+        Ok(())
+    }
+
+    fn remove(&self, number: u32) -> anyhow::Result<()> {
+        self.connection().execute("DELETE FROM entries WHERE number = ?1", params![number])?;
+
+        Ok(())
+    }
+
+    #[allow(clippy::significant_drop_tightening)] // the statement borrows the connection
+    fn for_each(&self, f: &mut dyn FnMut(&UnboxedEntry)) -> anyhow::Result<()> {
+        let connection = self.connection();
+
+        let mut statement = connection.prepare("SELECT package FROM entries")?;
+
+        let mut rows = statement.query(params![])?;
+
+        while let Some(row) = rows.next()? {
+            let package: Vec<u8> = row.get(0)?;
+
+            f(&deserialize_entry(&package)?);
+        }
+
+        Ok(())
+    }
+
+    fn mark_changed(&self, number: u32) -> anyhow::Result<()> {
+        self.connection()
+            .execute("INSERT OR IGNORE INTO changed (number) VALUES (?1)", params![number])?;
+
+        Ok(())
+    }
+
+    fn peek_changed(&self) -> anyhow::Result<Vec<u32>> {
+        let numbers = self
+            .connection()
+            .prepare("SELECT number FROM changed")?
+            .query_map(params![], |row| row.get(0))?
+            .collect::<Result<Vec<u32>, _>>()?;
+
+        Ok(numbers)
+    }
+
+    #[allow(clippy::significant_drop_tightening)] // the transaction borrows the connection
+    fn clear_changed(&self, numbers: &[u32]) -> anyhow::Result<()> {
+        let mut connection = self.connection();
+
+        let transaction = connection.transaction()?;
+
+        for number in numbers {
+            transaction.execute("DELETE FROM changed WHERE number = ?1", params![number])?;
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    fn count_changed(&self) -> anyhow::Result<usize> {
+        let n_changed: i64 =
+            self.connection().query_row("SELECT COUNT(*) FROM changed", params![], |row| {
+                row.get(0)
+            })?;
+
+        Ok(n_changed as usize)
+    }
+}
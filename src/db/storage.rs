@@ -0,0 +1,64 @@
+use crate::{Entries, UnboxedEntry};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageBackend {
+    /// The entries are kept in memory and written to a file of serialized
+    /// `PeerReply` packages
+    File,
+    /// The entries are kept in an SQLite database
+    Sqlite,
+}
+
+impl std::str::FromStr for StorageBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> anyhow::Result<Self> {
+        match input {
+            "file" => Ok(Self::File),
+            "sqlite" => Ok(Self::Sqlite),
+
+            _ => Err(anyhow!("unknown storage backend: `{}`", input)),
+        }
+    }
+}
+
+/// A place the entries of the directory are stored in.
+///
+/// NOTE: implementations are not required to be reentrant, so the callbacks
+/// passed to a `Storage` must not call back into it.
+pub trait Storage: Send + Sync {
+    /// Restore the stored entries after a restart
+    fn load(&self) -> anyhow::Result<()>;
+
+    /// Make sure all entries are durably stored
+    fn persist(&self) -> anyhow::Result<()>;
+
+    fn get(&self, number: u32) -> anyhow::Result<Option<UnboxedEntry>>;
+
+    /// Insert `entry`, overwriting the entry with the same number
+    fn insert(&self, entry: UnboxedEntry) -> anyhow::Result<()>;
+
+    fn remove(&self, number: u32) -> anyhow::Result<()>;
+
+    fn for_each(&self, f: &mut dyn FnMut(&UnboxedEntry)) -> anyhow::Result<()>;
+
+    /// Mark the entry with `number` as needing to be sent to the other servers
+    fn mark_changed(&self, number: u32) -> anyhow::Result<()>;
+
+    /// Return the numbers of all changed entries
+    fn peek_changed(&self) -> anyhow::Result<Vec<u32>>;
+
+    /// Unmark the entries with `numbers` as changed, after they were handed
+    /// on to be sent to the other servers
+    fn clear_changed(&self, numbers: &[u32]) -> anyhow::Result<()>;
+
+    fn count_changed(&self) -> anyhow::Result<usize>;
+
+    fn entries(&self) -> anyhow::Result<Entries> {
+        let mut entries = Vec::new();
+
+        self.for_each(&mut |entry| entries.push(entry.clone()))?;
+
+        Ok(entries)
+    }
+}
@@ -0,0 +1,190 @@
+//! Bulk import and export of entries as JSON or CSV.
+
+use super::{
+    get_entry_by_number, update_entry, update_entry_if_newer, validate_entry, ChangeSource,
+};
+use crate::{get_current_itelex_timestamp, UnboxedEntry};
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferFormat {
+    Json,
+    Csv,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+    /// Only import entries that are newer than the stored ones
+    Merge,
+    /// Import all entries, giving them the current timestamp
+    Overwrite,
+}
+
+/// An entry as a row of a CSV file
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct CsvRow {
+    number: u32,
+    name: String,
+    client_type: u8,
+    hostname: Option<String>,
+    ipaddress: Option<String>,
+    port: u16,
+    extension: u8,
+    disabled: bool,
+    timestamp: u32,
+}
+
+impl CsvRow {
+    fn from_entry(entry: &UnboxedEntry) -> Self {
+        Self {
+            number: entry.number,
+            name: entry.name.0.to_string(),
+            client_type: entry.client_type as u8,
+            hostname: entry.hostname().map(String::from),
+            ipaddress: entry.ipaddress().map(|ipaddress| ipaddress.to_string()),
+            port: entry.port,
+            extension: entry.extension,
+            disabled: entry.disabled(),
+            timestamp: entry.timestamp,
+        }
+    }
+
+    fn into_entry(self) -> anyhow::Result<UnboxedEntry> {
+        // NOTE: we go through the same JSON representation the web interface uses
+        Ok(serde_json::from_value(serde_json::json!({
+            "number": self.number,
+            "name": self.name,
+            "client_type": self.client_type,
+            "hostname": self.hostname,
+            "ipaddress": self.ipaddress,
+            "port": self.port,
+            "extension": self.extension,
+            "flags": if self.disabled { 2 } else { 0 },
+            "timestamp": self.timestamp,
+            "pin": 0,
+        }))?)
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct RowError {
+    /// The number of the row, starting at 1
+    pub row: usize,
+    pub error: String,
+}
+
+#[derive(serde::Serialize, Debug, Default)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub n_rows: usize,
+    /// The number of entries that were (or would have been) written
+    pub n_imported: usize,
+    pub errors: Vec<RowError>,
+}
+
+/// Format `entries` as a JSON array or as CSV with a header row
+pub fn export_entries(format: TransferFormat, entries: &[UnboxedEntry]) -> anyhow::Result<String> {
+    match format {
+        TransferFormat::Json => Ok(serde_json::to_string(entries)?),
+        TransferFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+
+            for entry in entries {
+                writer.serialize(CsvRow::from_entry(entry))?;
+            }
+
+            Ok(String::from_utf8(writer.into_inner()?)?)
+        }
+    }
+}
+
+/// Split `data` into rows, each of which either is an entry or the reason why
+/// it isn't
+fn parse_rows(
+    format: TransferFormat,
+    data: &str,
+) -> anyhow::Result<Vec<anyhow::Result<UnboxedEntry>>> {
+    Ok(match format {
+        TransferFormat::Json => {
+            let rows: Vec<serde_json::Value> = serde_json::from_str(data)?;
+
+            rows.into_iter()
+                .map(|mut row| {
+                    if let Some(row) = row.as_object_mut() {
+                        // pins are not imported, so they may be left out
+                        row.entry("pin").or_insert_with(|| 0.into());
+                    }
+
+                    Ok(serde_json::from_value::<UnboxedEntry>(row)?)
+                })
+                .collect()
+        }
+        TransferFormat::Csv => csv::Reader::from_reader(data.as_bytes())
+            .deserialize::<CsvRow>()
+            .map(|row| row?.into_entry())
+            .collect(),
+    })
+}
+
+/// Import the entries in `data`, reporting every row that could not be
+/// imported.
+///
+/// Pins are never imported: imported entries keep the pin of the entry they
+/// replace.
+pub fn import_entries(
+    format: TransferFormat,
+    mode: ImportMode,
+    dry_run: bool,
+    data: &str,
+    source: ChangeSource,
+) -> anyhow::Result<ImportReport> {
+    let rows = parse_rows(format, data)?;
+
+    let mut report = ImportReport { dry_run, n_rows: rows.len(), ..Default::default() };
+
+    for (i, row) in rows.into_iter().enumerate() {
+        let result = row.and_then(|mut entry| {
+            validate_entry(&entry)?;
+
+            let existing = get_entry_by_number(entry.number)?;
+
+            entry.pin = existing.as_ref().map_or(0, |existing| existing.pin);
+
+            match mode {
+                ImportMode::Merge => {
+                    if dry_run {
+                        Ok(existing.is_none_or(|existing| existing.timestamp < entry.timestamp))
+                    } else {
+                        update_entry_if_newer(Box::new(entry), source.clone())
+                    }
+                }
+                ImportMode::Overwrite => {
+                    entry.timestamp = get_current_itelex_timestamp();
+
+                    if !dry_run {
+                        update_entry(Box::new(entry), source.clone())?;
+                    }
+
+                    Ok(true)
+                }
+            }
+        });
+
+        match result {
+            Ok(true) => report.n_imported += 1,
+            Ok(false) => {}
+            Err(err) => report.errors.push(RowError { row: i + 1, error: format!("{:#}", err) }),
+        }
+    }
+
+    info!(
+        "imported {} of {} entries with {} errors{}",
+        report.n_imported,
+        report.n_rows,
+        report.errors.len(),
+        if dry_run { " (dry run)" } else { "" }
+    );
+
+    Ok(report)
+}
@@ -0,0 +1,181 @@
+//! Checks that an entry is consistent before it is written to the database.
+
+use crate::{ClientType, PackageBody, UnboxedEntry};
+use std::fmt;
+
+/// The highest valid extension code, which stands for the extension `0`
+const MAX_EXTENSION: u8 = 110;
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct FieldError {
+    /// The name of the field of the entry, as in its JSON representation
+    pub field: &'static str,
+    pub message: String,
+}
+
+/// All the reasons why an entry can't be written to the database
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct ValidationError {
+    pub number: u32,
+    pub errors: Vec<FieldError>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "entry {} is invalid: ", self.number)?;
+
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+
+            write!(f, "{}: {}", error.field, error.message)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Check that `entry` can be serialized and that it contains everything that
+/// is needed to reach the teletype it describes.
+///
+/// Deleted entries are only checked for their format, since they are never
+/// handed out.
+pub fn validate_entry(entry: &UnboxedEntry) -> Result<(), ValidationError> {
+    let mut errors = Vec::new();
+
+    let mut error = |field, message: &str| {
+        errors.push(FieldError { field, message: message.to_string() });
+    };
+
+    if let Err(err) = entry.serialize(&mut Vec::new()) {
+        error("entry", &format!("has an invalid format: {:?}", err));
+    }
+
+    if entry.number == 0 {
+        error("number", "must not be 0");
+    }
+
+    if entry.client_type != ClientType::Deleted {
+        if entry.name.0.trim().is_empty() {
+            error("name", "must not be empty");
+        }
+
+        if entry.extension > MAX_EXTENSION {
+            error("extension", "is not a valid extension code");
+        }
+
+        let has_hostname = entry.hostname().is_some_and(|hostname| !hostname.trim().is_empty());
+        let has_ipaddress = !entry.ipaddress.is_unspecified();
+
+        match entry.client_type {
+            ClientType::BaudotHostname | ClientType::AsciiHostname | ClientType::Email
+                if !has_hostname =>
+            {
+                error("hostname", "is required for this client type");
+            }
+            ClientType::BaudotFixedIp | ClientType::AsciiFixedIp | ClientType::BaudotDynIp
+                if !has_ipaddress =>
+            {
+                error("ipaddress", "is required for this client type");
+            }
+            _ => {}
+        }
+
+        if entry.client_type != ClientType::Email && entry.port == 0 {
+            error("port", "must not be 0");
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationError { number: entry.number, errors })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_entry;
+    use std::net::Ipv4Addr;
+
+    /// What is checked, how the valid test entry is changed for it and the
+    /// fields that should be invalid afterwards
+    type Case = (&'static str, fn(&mut UnboxedEntry), Vec<&'static str>);
+
+    /// The fields `validate_entry` complains about
+    fn invalid_fields(entry: &UnboxedEntry) -> Vec<&'static str> {
+        match validate_entry(entry) {
+            Ok(()) => Vec::new(),
+            Err(err) => err.errors.iter().map(|error| error.field).collect(),
+        }
+    }
+
+    #[test]
+    fn checks_entries() {
+        let cases: Vec<Case> = vec![
+            ("valid", |_| {}, vec![]),
+            ("number 0", |entry| entry.number = 0, vec!["number"]),
+            ("empty name", |entry| entry.name = " ".into(), vec!["name"]),
+            (
+                "hostname type without hostname",
+                |entry| entry.client_type = ClientType::BaudotHostname,
+                vec!["hostname"],
+            ),
+            (
+                "hostname type with hostname",
+                |entry| {
+                    entry.client_type = ClientType::AsciiHostname;
+                    entry.hostname = "telex.example.com".into();
+                },
+                vec![],
+            ),
+            (
+                "ip type with 0.0.0.0",
+                |entry| entry.ipaddress = Ipv4Addr::UNSPECIFIED,
+                vec!["ipaddress"],
+            ),
+            ("extension 110", |entry| entry.extension = 110, vec![]),
+            ("extension 111", |entry| entry.extension = 111, vec!["extension"]),
+            ("port 0", |entry| entry.port = 0, vec!["port"]),
+            (
+                "email with port 0",
+                |entry| {
+                    entry.client_type = ClientType::Email;
+                    entry.hostname = "telex@example.com".into();
+                    entry.port = 0;
+                },
+                vec![],
+            ),
+            (
+                "deleted entries are only checked for their format",
+                |entry| {
+                    entry.client_type = ClientType::Deleted;
+                    entry.name = "".into();
+                    entry.ipaddress = Ipv4Addr::UNSPECIFIED;
+                    entry.extension = 111;
+                    entry.port = 0;
+                },
+                vec![],
+            ),
+            (
+                "deleted entries with number 0",
+                |entry| {
+                    entry.client_type = ClientType::Deleted;
+                    entry.number = 0;
+                },
+                vec!["number"],
+            ),
+        ];
+
+        for (name, change, expected) in cases {
+            let mut entry = test_entry(1234, 0);
+            change(&mut entry);
+
+            assert_eq!(invalid_fields(&entry), expected, "{}", name);
+        }
+    }
+}
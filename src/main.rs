@@ -13,6 +13,7 @@ macro_rules! config {
 
 #[macro_use]
 pub mod telex_server;
+pub mod cli;
 pub mod config;
 pub mod db;
 pub mod web_server;
@@ -26,7 +27,6 @@ use futures::{
     channel::{mpsc, oneshot},
     future::{select_all, FutureExt},
     select,
-    sink::SinkExt,
     stream::StreamExt,
 };
 pub use itelex::{
@@ -56,8 +56,6 @@ pub type UnboxedEntry = PeerReply;
 pub type Entries = Vec<PeerReply>;
 
 // global state
-pub static CHANGED: Lazy<DashMap<u32, ()>> = Lazy::new(DashMap::new);
-pub static DATABASE: Lazy<DashMap<u32, UnboxedEntry>> = Lazy::new(DashMap::new);
 pub static CONFIG: OnceCell<Config> = OnceCell::new();
 pub static TASKS: Lazy<DashMap<TaskId, ResultJoinHandle>> = Lazy::new(DashMap::new);
 pub static TASK_ID_COUNTER: Lazy<Mutex<TaskId>> = Lazy::new(|| Mutex::new(0));
@@ -74,10 +72,14 @@ async fn main() -> anyhow::Result<()> {
 
     CONFIG.set(Config::from_env().await?).expect("Failed to set config");
 
-    init_logger()?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    init_logger(!args.is_empty())?;
 
     debug!("using config: {:#?}", CONFIG.get().unwrap());
 
+    init_storage().context("Failed to open the database")?;
+
     if let Err(err) = read_db_from_disk().await {
         let err = err.context("Failed to restore DB from disk");
         error!("{:?}", err);
@@ -85,6 +87,10 @@ async fn main() -> anyhow::Result<()> {
         bail!(err);
     }
 
+    if cli::run(&args).await? {
+        return Ok(());
+    }
+
     let (stop_itelex_server, stopped_itelex_server) = oneshot::channel();
     let (stop_web_server, stopped_web_server) = oneshot::channel();
 
@@ -141,7 +147,9 @@ async fn wait_for_task(task_id: usize) -> anyhow::Result<()> {
     task.await?
 }
 
-fn init_logger() -> anyhow::Result<()> {
+/// Initialize the logger. When running a command line subcommand, logs go to
+/// stderr, so that they don't mix with the command's output
+fn init_logger(cli: bool) -> anyhow::Result<()> {
     use simplelog::{
         CombinedLogger, Config, LevelFilter, SharedLogger, TermLogger, TerminalMode, WriteLogger,
     };
@@ -169,6 +177,8 @@ fn init_logger() -> anyhow::Result<()> {
     {
         let log_level = if let Some(log_level) = config!(LOG_LEVEL_TERM).as_ref() {
             log_level_from_string(log_level)?
+        } else if cli {
+            LevelFilter::Warn
         } else {
             #[cfg(debug_assertions)]
             let default_level = LevelFilter::Debug;
@@ -179,7 +189,9 @@ fn init_logger() -> anyhow::Result<()> {
             default_level
         };
 
-        loggers.push(TermLogger::new(log_level, Config::default(), TerminalMode::Mixed));
+        let terminal_mode = if cli { TerminalMode::Stderr } else { TerminalMode::Mixed };
+
+        loggers.push(TermLogger::new(log_level, Config::default(), terminal_mode));
     }
 
     if let Some(log_file_path) = config!(LOG_FILE_PATH).as_ref() {
@@ -0,0 +1,86 @@
+//! The replies of the ASCII protocol, which the web server also offers for
+//! scripts that don't want to speak it.
+
+use super::ClientType;
+use crate::UnboxedEntry;
+use anyhow::Context;
+use std::fmt;
+
+/// The fields of an entry that are sent in reply to an ASCII query
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct AsciiEntry {
+    pub number: u32,
+    pub name: String,
+    #[serde(serialize_with = "serialize_client_type")]
+    pub client_type: ClientType,
+    /// The hostname of the entry, or its ip address if it has none
+    pub address: String,
+    pub port: u16,
+    pub extension: String,
+}
+
+fn serialize_client_type<S>(client_type: &ClientType, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_u8(*client_type as u8)
+}
+
+impl AsciiEntry {
+    pub fn from_entry(entry: &UnboxedEntry) -> anyhow::Result<Self> {
+        let address = if let Some(hostname) = entry.hostname() {
+            String::from(hostname)
+        } else {
+            let ipaddress = entry
+                .ipaddress()
+                .context("database is incosistent: entry has neither hostname nor ipaddress")?;
+
+            format!("{}", ipaddress)
+        };
+
+        Ok(Self {
+            number: entry.number,
+            name: entry.name.0.clone(),
+            client_type: entry.client_type,
+            address,
+            port: entry.port,
+            extension: entry
+                .extension_as_str()
+                .map_err(|ext| anyhow!(format!("ivalid extension: {}", ext)))?
+                .to_string(),
+        })
+    }
+}
+
+/// The reply to an ASCII query that found `self`
+impl fmt::Display for AsciiEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ok\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n+++\r\n",
+            self.number, self.name, self.client_type, self.address, self.port, self.extension,
+        )
+    }
+}
+
+/// The reply to an ASCII query for `query` that found nothing
+pub fn not_found(query: impl fmt::Display) -> String {
+    format!("fail\r\n{}\r\nunknown\r\n+++\r\n", query)
+}
+
+/// The reply to an ASCII query that found `entries`
+pub fn format_entries(entries: &[UnboxedEntry]) -> anyhow::Result<String> {
+    let mut reply = String::new();
+
+    for entry in entries {
+        reply.push_str(&AsciiEntry::from_entry(entry)?.to_string());
+    }
+
+    Ok(reply)
+}
+
+/// The reply to an ASCII search for `pattern`, which is too short to be
+/// searched for
+pub fn pattern_too_short(pattern: impl fmt::Display) -> String {
+    format!("fail\r\n{}\r\npattern too short\r\n+++\r\n", pattern)
+}
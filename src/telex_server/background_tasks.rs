@@ -1,4 +1,179 @@
 use super::*;
+use std::{collections::HashMap, sync::Mutex as SyncMutex};
+
+/// Is sent how the next attempt of a peer task to send its outbox went
+type PushWaiter = oneshot::Sender<Result<(), String>>;
+
+/// Tell everyone waiting how the last attempt to send an outbox went
+fn report(waiters: &mut Vec<PushWaiter>, result: Result<(), String>) {
+    for waiter in waiters.drain(..) {
+        let _ = waiter.send(result.clone());
+    }
+}
+
+/// A task that sends the outbox of a peer to it
+struct PeerTask {
+    /// Wakes the task up to send the outbox, optionally waiting for the
+    /// result. Dropping it stops the task
+    notify: mpsc::UnboundedSender<Option<PushWaiter>>,
+    /// Interrupts the task while it waits to retry
+    abort: oneshot::Sender<()>,
+    handle: VoidJoinHandle,
+}
+
+/// The running peer tasks by the host of their peer
+static PEER_TASKS: Lazy<SyncMutex<HashMap<String, PeerTask>>> =
+    Lazy::new(|| SyncMutex::new(HashMap::new()));
+
+type PeersChangedChannel =
+    (mpsc::UnboundedSender<()>, SyncMutex<Option<mpsc::UnboundedReceiver<()>>>);
+
+/// Signals that the peer list changed and the peer tasks have to be updated.
+///
+/// NOTE: the web server does not run on the tokio runtime, so it can't spawn
+/// the peer tasks itself
+static PEERS_CHANGED: Lazy<PeersChangedChannel> = Lazy::new(|| {
+    let (sender, receiver) = mpsc::unbounded();
+    (sender, SyncMutex::new(Some(receiver)))
+});
+
+/// Start and stop the peer tasks after the peer list was changed
+pub fn peers_changed() {
+    let _ = PEERS_CHANGED.0.unbounded_send(());
+}
+
+/// A background task an admin can run immediately instead of waiting for it
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    SyncDb,
+    SyncChanged,
+    FullQuery,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Job {
+    pub id: u64,
+    pub kind: JobKind,
+    /// The host of the only peer the job is run for
+    pub peer: Option<String>,
+    /// The itelex timestamp of when the job was started
+    pub started: u32,
+    /// The itelex timestamp of when the job finished
+    pub finished: Option<u32>,
+    pub error: Option<String>,
+}
+
+/// How many finished jobs are kept to be looked up
+const MAX_FINISHED_JOBS: usize = 100;
+
+static JOBS: Lazy<SyncMutex<Vec<Job>>> = Lazy::new(|| SyncMutex::new(Vec::new()));
+
+type JobChannel = (mpsc::UnboundedSender<Job>, SyncMutex<Option<mpsc::UnboundedReceiver<Job>>>);
+
+/// The jobs that still have to be run.
+///
+/// NOTE: like `PEERS_CHANGED`, this lets the web server start jobs on the tokio
+/// runtime
+static JOB_QUEUE: Lazy<JobChannel> = Lazy::new(|| {
+    let (sender, receiver) = mpsc::unbounded();
+    (sender, SyncMutex::new(Some(receiver)))
+});
+
+fn lock_jobs() -> std::sync::MutexGuard<'static, Vec<Job>> {
+    JOBS.lock().expect("the job list lock was poisoned")
+}
+
+/// Run the job `kind` (only for `peer`, if it is given) as soon as possible,
+/// returning its id
+pub fn start_job(kind: JobKind, peer: Option<String>) -> anyhow::Result<u64> {
+    // NOTE: without a server pin, the database is neither written to disk nor
+    // sent to other servers
+    if config!(SERVER_PIN) == 0 && kind != JobKind::FullQuery {
+        bail!(anyhow!("this job can't be run without a server pin"));
+    }
+
+    if let Some(peer) = &peer {
+        if kind == JobKind::SyncDb {
+            bail!(anyhow!("the database can't be synced for a single peer"));
+        }
+
+        if !peers::active_peers().contains(peer) {
+            bail!(anyhow!("{} is not an active peer", peer));
+        }
+    }
+
+    let mut jobs = lock_jobs();
+
+    if jobs.iter().any(|job| job.finished.is_none() && job.kind == kind && job.peer == peer) {
+        bail!(anyhow!("the same job is still running"));
+    }
+
+    let id = jobs.last().map_or(0, |job| job.id + 1);
+
+    let job = Job {
+        id,
+        kind,
+        peer,
+        started: get_current_itelex_timestamp(),
+        finished: None,
+        error: None,
+    };
+
+    JOB_QUEUE.0.unbounded_send(job.clone()).map_err(|_| anyhow!("jobs can't be run anymore"))?;
+
+    info!("started job {}: {:?}", id, job);
+
+    jobs.push(job);
+
+    let mut n_forgotten =
+        jobs.iter().filter(|job| job.finished.is_some()).count().saturating_sub(MAX_FINISHED_JOBS);
+
+    jobs.retain(|job| {
+        if n_forgotten > 0 && job.finished.is_some() {
+            n_forgotten -= 1;
+            false
+        } else {
+            true
+        }
+    });
+
+    drop(jobs);
+
+    Ok(id)
+}
+
+pub fn get_job(id: u64) -> Option<Job> {
+    lock_jobs().iter().find(|job| job.id == id).cloned()
+}
+
+/// The recent jobs, oldest first
+pub fn get_jobs() -> Vec<Job> {
+    lock_jobs().clone()
+}
+
+async fn run_job(job: Job) {
+    let result = match (job.kind, job.peer.as_deref()) {
+        (JobKind::SyncDb, _) => sync_db_to_disk().await,
+        (JobKind::SyncChanged, None) => sync_changed().await,
+        (JobKind::SyncChanged, Some(peer)) => match sync_changed().await {
+            Ok(()) => push_with_peer_task(peer).await,
+            Err(err) => Err(err),
+        },
+        (JobKind::FullQuery, None) => reconcile().await,
+        (JobKind::FullQuery, Some(peer)) => full_query_peer(peer).await,
+    };
+
+    match &result {
+        Ok(()) => info!("finished job {}", job.id),
+        Err(err) => error!("job {} failed: {:?}", job.id, err),
+    }
+
+    if let Some(finished) = lock_jobs().iter_mut().find(|finished| finished.id == job.id) {
+        finished.finished = Some(get_current_itelex_timestamp());
+        finished.error = result.err().map(|err| format!("{:#}", err));
+    }
+}
 
 // TODO: refactor
 pub fn start_background_tasks() -> (Vec<VoidJoinHandle>, Vec<oneshot::Sender<()>>) {
@@ -8,12 +183,64 @@ pub fn start_background_tasks() -> (Vec<VoidJoinHandle>, Vec<oneshot::Sender<()>
 
     let mut abort_senders = Vec::new();
 
-    let (server_join_handles, mut server_senders, server_abort_senders) =
-        update_other_servers(config!(SERVERS).to_vec());
+    let name = "update peer tasks";
+    let (abort_sender, abort_receiver) = oneshot::channel();
+    abort_senders.push(abort_sender);
+    join_handles.push(task::spawn(async move {
+        let mut peers_changed = PEERS_CHANGED
+            .1
+            .lock()
+            .expect("the peers changed lock was poisoned")
+            .take()
+            .expect("the peer tasks can only be updated by one task");
+        info!("starting {:?} background task", name);
+        start_peer_tasks().await;
+        let mut exit = abort_receiver.fuse();
+        loop {
+            select! {
+                _ = exit => break,
+                changed = peers_changed.next() => {
+                    if changed.is_none() {
+                        break;
+                    }
 
-    abort_senders.extend(server_abort_senders);
+                    debug!("running background task {:?}", name);
+                    start_peer_tasks().await;
+                },
+            }
+        }
+        info!("stopped {:?} background task", name);
+    }));
 
-    join_handles.extend(server_join_handles);
+    let name = "run jobs";
+    let (abort_sender, abort_receiver) = oneshot::channel();
+    abort_senders.push(abort_sender);
+    join_handles.push(task::spawn(async move {
+        let mut job_queue = JOB_QUEUE
+            .1
+            .lock()
+            .expect("the job queue lock was poisoned")
+            .take()
+            .expect("jobs can only be run by one task");
+        info!("starting {:?} background task", name);
+        let mut exit = abort_receiver.fuse();
+        loop {
+            select! {
+                _ = exit => break,
+                job = job_queue.next() => {
+                    let job = match job {
+                        Some(job) => job,
+                        None => break,
+                    };
+
+                    debug!("running background task {:?}", name);
+                    // NOTE: jobs are run concurrently, since a full query can take a while
+                    task::spawn(run_job(job));
+                },
+            }
+        }
+        info!("stopped {:?} background task", name);
+    }));
 
     let name = "sync db";
     let (abort_sender, abort_receiver) = oneshot::channel();
@@ -47,7 +274,7 @@ pub fn start_background_tasks() -> (Vec<VoidJoinHandle>, Vec<oneshot::Sender<()>
         let mut exit = abort_receiver.fuse();
         loop {
             debug!("running background task {:?}", name);
-            if let Err(err) = sync_changed(&mut server_senders).await {
+            if let Err(err) = sync_changed().await {
                 error!(
                     "{:?}",
                     anyhow!(err).context(format!("failed to run background task {}", name))
@@ -70,7 +297,7 @@ pub fn start_background_tasks() -> (Vec<VoidJoinHandle>, Vec<oneshot::Sender<()>
         let mut exit = abort_receiver.fuse();
         loop {
             debug!("running background task {:?}", name);
-            if let Err(err) = full_query().await {
+            if let Err(err) = reconcile().await {
                 error!(
                     "{:?}",
                     anyhow!(err).context(format!("failed to run background task {}", name))
@@ -89,7 +316,7 @@ pub fn start_background_tasks() -> (Vec<VoidJoinHandle>, Vec<oneshot::Sender<()>
     (join_handles, abort_senders)
 }
 
-async fn full_query_for_server(server: SocketAddr) -> anyhow::Result<()> {
+async fn full_query_for_server(server: &str) -> anyhow::Result<()> {
     debug!("starting full query for server {}", server);
 
     let mut client = connect_to(server).await?;
@@ -115,13 +342,53 @@ async fn full_query_for_server(server: SocketAddr) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn full_query() -> anyhow::Result<()> {
+/// Send every server the entries it has not confirmed receiving from us and
+/// query the servers whose watermarks can't be trusted fully
+async fn reconcile() -> anyhow::Result<()> {
     let mut full_queries = Vec::new();
 
-    info!("starting full query");
+    info!("starting reconciliation");
+
+    let active_peers = peers::active_peers();
 
-    for server in config!(SERVERS).iter() {
-        full_queries.push(full_query_for_server(*server));
+    // NOTE: peers are matched by the address they connect to us from, so it has
+    // to be kept up to date even if we don't connect to them ourselves
+    for server in &active_peers {
+        if let Err(err) = peers::resolve_peer(server).await {
+            warn!("{:?}", err);
+        }
+    }
+
+    for server in active_peers {
+        if config!(SERVER_PIN) != 0 {
+            if let Some(mut entries) = replication::entries_since_confirmed(&server)? {
+                let pending = outbox::pending(&server);
+
+                entries.retain(|entry| {
+                    !pending.iter().any(|pending| pending.number == entry.number)
+                });
+
+                if !entries.is_empty() {
+                    info!("sending {} unconfirmed entries to server {}", entries.len(), server);
+
+                    outbox::enqueue(std::slice::from_ref(&server), &entries)?;
+                }
+            }
+
+            // NOTE: this also retries the outbox of a server whose task stopped
+            // retrying because the server was failing for too long
+            if outbox::count_pending(&server) > 0 {
+                notify_peer_task(&server);
+            }
+        }
+
+        if !peers::should_attempt(&server) {
+            debug!("skipping full query for server {}, it failed too often", server);
+        } else if replication::needs_full_query(&server) {
+            full_queries.push(async move { full_query_peer(&server).await });
+        } else {
+            debug!("skipping full query for server {}, its watermark is recent", server);
+        }
     }
 
     for result in futures::future::join_all(full_queries).await {
@@ -130,9 +397,9 @@ async fn full_query() -> anyhow::Result<()> {
         }
     }
 
-    info!("finished full query");
+    info!("finished reconciliation");
 
-    let n_changed = CHANGED.len();
+    let n_changed = count_changed_entries()?;
 
     if n_changed > 0 {
         warn!("Server has {} changed entries", n_changed);
@@ -143,13 +410,29 @@ async fn full_query() -> anyhow::Result<()> {
     Ok(()) //TODO
 }
 
-async fn connect_to(addr: SocketAddr) -> anyhow::Result<Client> {
-    info!("connecting to server at {}", addr);
+/// Query the peer at `server` fully and record how it went
+async fn full_query_peer(server: &str) -> anyhow::Result<()> {
+    if let Err(err) = full_query_for_server(server).await {
+        peers::record_failure(server, &err);
+
+        return Err(err);
+    }
+
+    peers::record_query(server);
+
+    replication::record_full_query(server)
+}
+
+/// Connect to the peer at `host`, looking up its address again first
+async fn connect_to(host: &str) -> anyhow::Result<Client> {
+    let addr = peers::resolve_peer(host).await?;
+
+    info!("connecting to server {} at {}", host, addr);
 
     Ok(Client::new(TcpStream::connect(addr).await?, addr))
 }
 
-async fn update_server_with_packages(server: SocketAddr, packages: Entries) -> anyhow::Result<()> {
+async fn update_server_with_packages(server: &str, packages: Entries) -> anyhow::Result<()> {
     if config!(SERVER_PIN) == 0 {
         bail!(anyhow!("Not updating other servers without a server pin"));
     }
@@ -167,93 +450,234 @@ async fn update_server_with_packages(server: SocketAddr, packages: Entries) -> a
     wait_for_task(task_id).await
 }
 
-fn update_other_servers(
-    servers: Vec<SocketAddr>,
-) -> (Vec<VoidJoinHandle>, Vec<mpsc::UnboundedSender<Entries>>, Vec<oneshot::Sender<()>>) {
-    let mut join_handles = Vec::new();
+/// Send the outbox of the peer at `server` to it, returning whether there was
+/// anything to send
+async fn push_outbox(server: &str) -> anyhow::Result<bool> {
+    // NOTE: this is read again on every retry, so that entries which changed
+    // while the server was unreachable are sent in their newest version
+    let packages = outbox::pending(server);
 
-    let mut senders = Vec::new();
+    if packages.is_empty() {
+        debug!("There are no packages to sync");
 
-    let mut abort_senders = Vec::new();
+        return Ok(false);
+    }
 
-    for server in servers {
-        let (abort_sender, abort_receiver) = oneshot::channel::<()>();
+    let n_packages = packages.len();
 
-        abort_senders.push(abort_sender);
+    update_server_with_packages(server, packages.clone()).await?;
 
-        let (sender, mut receiver) = mpsc::unbounded::<Entries>();
+    debug!("Sent {} packages to server {}", n_packages, server);
 
-        senders.push(sender);
+    peers::record_push(server);
 
-        join_handles.push(task::spawn(async move {
-            info!("started syncing server: {}", server);
+    if let Err(err) = outbox::acknowledge(server, &packages) {
+        error!("{:?}", err.context("Failed to update the outbox"));
+    }
 
-            let mut abort_receiver = abort_receiver.fuse();
+    if let Err(err) = replication::record_confirmed(server, &packages) {
+        error!("{:?}", err.context("Failed to update the watermark"));
+    }
 
-            // NOTE: receiver already implementes `FusedStream` and so does not need to be
-            // `fuse`ed
-            'outer: while let Some(mut packages) = receiver.next().await {
-                debug!("Received {} initial packages", packages.len());
+    Ok(true)
+}
 
-                tokio::time::delay_for(Duration::from_millis(10)).await;
+fn spawn_peer_task(server: String) -> PeerTask {
+    let (abort, abort_receiver) = oneshot::channel::<()>();
 
-                // Wait a bit, in case there are more packages on the way, but not yet in the
-                // channel TODO: should we really do this?
-                while let Ok(additional) = receiver.try_next() {
-                    if let Some(additional) = additional {
-                        debug!(
-                            "Extending queue for client by {} additional packages",
-                            additional.len()
-                        );
+    let (notify, mut receiver) = mpsc::unbounded::<Option<PushWaiter>>();
 
-                        packages.extend(additional);
-                    } else {
-                        break;
-                    }
-                }
+    // send the entries that were left in the outbox when the server was stopped
+    notify.unbounded_send(None).expect("the receiver was dropped");
 
-                // This should never happen and be handled be handled by senders,
-                // so that no task sync needs to take place:
-                // TODO: remove?
-                if packages.is_empty() {
-                    debug!("There are no packages to sync");
+    let handle = task::spawn(async move {
+        info!("started syncing server: {}", server);
 
-                    continue;
-                }
+        let mut abort_receiver = abort_receiver.fuse();
+
+        // NOTE: receiver already implementes `FusedStream` and so does not need to be
+        // `fuse`ed
+        'outer: while let Some(waiter) = receiver.next().await {
+            let mut waiters: Vec<PushWaiter> = waiter.into_iter().collect();
+
+            // Wait a bit, in case there are more entries on the way, but not yet in the
+            // outbox TODO: should we really do this?
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+
+            // the outbox already contains everything we were notified about
+            while let Ok(Some(waiter)) = receiver.try_next() {
+                waiters.extend(waiter);
+            }
 
-                while let Err(err) = update_server_with_packages(server, packages.clone()).await {
-                    error!(
-                        "{:?}",
-                        anyhow!(err).context(format!("Failed to update server {}", server))
-                    );
+            loop {
+                // NOTE: a server whose circuit is open is left alone until its
+                // retry time and then tried again once before it is closed
+                if !peers::should_attempt(&server) {
+                    let delay = peers::paused_for(&server);
 
-                    info!("retrying in: {:?}", config!(SERVER_COOLDOWN));
+                    info!("syncing server {} is paused, retrying in: {:?}", server, delay);
+
+                    report(&mut waiters, Err(format!("the server is paused for {:?}", delay)));
 
                     select! {
                         res = abort_receiver => if res.is_ok() { break 'outer; },
-                        _ = tokio::time::delay_for(config!(SERVER_COOLDOWN)).fuse() => {},
+                        _ = tokio::time::delay_for(delay).fuse() => continue,
+                    }
+                }
+
+                match push_outbox(&server).await {
+                    Ok(true) => {
+                        if (&mut abort_receiver).now_or_never() == Some(Ok(())) {
+                            break 'outer;
+                        }
+                    }
+                    Ok(false) => {
+                        report(&mut waiters, Ok(()));
+
+                        break;
+                    }
+                    Err(err) => {
+                        let delay = peers::record_failure(&server, &err);
+
+                        report(&mut waiters, Err(format!("{:#}", err)));
+
+                        error!(
+                            "{:?}",
+                            anyhow!(err).context(format!("Failed to update server {}", server))
+                        );
+
+                        if peers::should_give_up(&server) {
+                            // NOTE: the entries stay in the outbox and are sent once the
+                            // task is notified again, at the latest by the next reconciliation
+                            warn!(
+                                "not retrying to send {} entries to server {} until the next \
+                                 reconciliation, it has been failing for more than {:?}",
+                                outbox::count_pending(&server),
+                                server,
+                                config!(SERVER_GIVE_UP_AFTER),
+                            );
+
+                            break;
+                        }
+
+                        info!("retrying in: {:?}", delay);
+
+                        select! {
+                            res = abort_receiver => if res.is_ok() { break 'outer; },
+                            _ = tokio::time::delay_for(delay).fuse() => {},
+                        }
                     }
                 }
             }
+        }
+
+        info!("stopped syncing server: {}", server);
+    });
 
-            info!("stopped syncing server: {}", server);
-        }));
+    PeerTask { notify, abort, handle }
+}
+
+/// Start a peer task for every active peer that has none yet and stop the
+/// tasks of peers that were disabled or removed.
+///
+/// NOTE: this waits for the stopped tasks to finish their current update, so
+/// that a peer that is re-enabled right away is never synced by two tasks
+#[allow(clippy::significant_drop_tightening)] // the lock must not be held across the await
+async fn start_peer_tasks() {
+    let active_peers = peers::active_peers();
+
+    let stopped: Vec<PeerTask> = {
+        let mut peer_tasks = PEER_TASKS.lock().expect("the peer task lock was poisoned");
+
+        let stopped: Vec<String> =
+            peer_tasks.keys().filter(|server| !active_peers.contains(server)).cloned().collect();
+
+        stopped.iter().filter_map(|server| peer_tasks.remove(server)).collect()
+    };
+
+    let mut handles = Vec::new();
+
+    for peer_task in stopped {
+        let _ = peer_task.abort.send(());
+        handles.push(peer_task.handle);
     }
 
-    (join_handles, senders, abort_senders)
+    futures::future::join_all(handles).await;
+
+    let mut peer_tasks = PEER_TASKS.lock().expect("the peer task lock was poisoned");
+
+    for server in active_peers {
+        peer_tasks.entry(server.clone()).or_insert_with(|| spawn_peer_task(server));
+    }
+}
+
+/// Stop all peer tasks and wait for them to finish
+pub async fn stop_peer_tasks() {
+    let peer_tasks: Vec<PeerTask> = PEER_TASKS
+        .lock()
+        .expect("the peer task lock was poisoned")
+        .drain()
+        .map(|(_, peer_task)| peer_task)
+        .collect();
+
+    let mut handles = Vec::new();
+
+    for peer_task in peer_tasks {
+        let _ = peer_task.abort.send(());
+        handles.push(peer_task.handle);
+    }
+
+    futures::future::join_all(handles).await;
 }
 
-async fn sync_changed(
-    server_senders: &mut Vec<mpsc::UnboundedSender<Entries>>,
-) -> anyhow::Result<()> {
-    let changed = get_changed_entries();
+fn notify_peer_task(server: &str) {
+    if let Some(peer_task) =
+        PEER_TASKS.lock().expect("the peer task lock was poisoned").get(server)
+    {
+        let _ = peer_task.notify.unbounded_send(None);
+    }
+}
+
+/// Make the peer task of `server` send its outbox now and wait for the result
+async fn push_with_peer_task(server: &str) -> anyhow::Result<()> {
+    let (sender, receiver) = oneshot::channel();
+
+    PEER_TASKS
+        .lock()
+        .expect("the peer task lock was poisoned")
+        .get(server)
+        .ok_or_else(|| anyhow!("server {} is not being synced", server))?
+        .notify
+        .unbounded_send(Some(sender))
+        .map_err(|_| anyhow!("server {} is not being synced anymore", server))?;
+
+    receiver
+        .await
+        .map_err(|_| anyhow!("syncing server {} was stopped", server))?
+        .map_err(|err| anyhow!(err))
+}
+
+async fn sync_changed() -> anyhow::Result<()> {
+    if config!(SERVER_PIN) == 0 {
+        // we can't update other servers without a server pin
+        return Ok(());
+    }
+
+    let changed = get_changed_entries()?;
 
     if changed.is_empty() {
         return Ok(());
     }
 
-    for sender in server_senders {
-        sender.send(changed.clone()).await?;
+    let active_peers = peers::active_peers();
+
+    outbox::enqueue(&active_peers, &changed)?;
+
+    // NOTE: only now that they are queued, they may be forgotten as changed
+    clear_changed_entries(&changed)?;
+
+    for server in &active_peers {
+        notify_peer_task(server);
     }
 
     Ok(())
@@ -1,10 +1,27 @@
-use super::{FULL_QUERY_VERSION, LOGIN_VERSION, PEER_SEARCH_VERSION};
+use super::{ascii, FULL_QUERY_VERSION, LOGIN_VERSION, PEER_SEARCH_VERSION};
 use crate::{db::*, errors::ItelexServerErrorKind, Entries, CONFIG};
 use anyhow::Context;
-use futures::{future::FutureExt, select, stream::StreamExt};
+use futures::{future::FutureExt, select};
 use itelex::{server::*, Package};
 use std::net::{IpAddr, SocketAddr};
-use tokio::{io::BufReader, net::TcpStream, prelude::*};
+use tokio::{net::TcpStream, prelude::*, task};
+
+/// How many entries the ASCII `c` command lists if no count is given
+const DEFAULT_ASCII_CHANGED_COUNT: usize = 10;
+const MAX_ASCII_CHANGED_COUNT: usize = 100;
+
+const ASCII_HELP: &str = "ok\r\n\
+                          q<number>: look up a number\r\n\
+                          s<name>: search for names with a word of at least 3 letters\r\n\
+                          c[<count>]: list the most recently changed entries\r\n\
+                          v: show the server version\r\n\
+                          h: show this help\r\n\
+                          m: keep the connection open for more queries\r\n\
+                          x: close the connection\r\n\
+                          +++\r\n";
+
+/// How long an ASCII line may get before the client is disconnected
+const MAX_ASCII_LINE_LENGTH: usize = 1024;
 
 #[derive(Debug, PartialEq, Eq)]
 
@@ -29,6 +46,11 @@ pub struct Client {
     pub mode: Mode,
     pub state: State,
     pub send_queue: Entries,
+    /// What was read from an ASCII client, but not handled yet
+    pub ascii_buffer: Vec<u8>,
+    /// Whether the ASCII client asked to keep the connection open for more
+    /// queries
+    pub ascii_session: bool,
 }
 
 impl Drop for Client {
@@ -41,7 +63,15 @@ impl Drop for Client {
 
 impl Client {
     pub const fn new(socket: TcpStream, address: SocketAddr) -> Self {
-        Self { socket, address, mode: Mode::Unknown, state: State::Idle, send_queue: Vec::new() }
+        Self {
+            socket,
+            address,
+            mode: Mode::Unknown,
+            state: State::Idle,
+            send_queue: Vec::new(),
+            ascii_buffer: Vec::new(),
+            ascii_session: false,
+        }
     }
 
     pub async fn handle(&mut self) -> anyhow::Result<()> {
@@ -69,6 +99,11 @@ impl Client {
                 {
                     select! {
                         _ = tokio::time::delay_for(config!(CLIENT_TIMEOUT)).fuse() => {
+                            if self.ascii_session {
+                                debug!("ascii session with {} timed out", self.address);
+                                break;
+                            }
+
                             Err(ItelexServerErrorKind::Timeout)?;
                         }
                         res = self.consume_package().fuse() => {
@@ -177,76 +212,177 @@ impl Client {
         }
     }
 
-    pub async fn consume_package_ascii(self: &mut Self) -> anyhow::Result<()> {
-        let mut lines = BufReader::new(&mut self.socket).lines();
+    /// Read the next line an ASCII client sent, keeping whatever it sent after
+    /// it for the next call
+    async fn read_line_ascii(&mut self) -> anyhow::Result<String> {
+        loop {
+            if let Some(end) = self.ascii_buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = self.ascii_buffer.drain(..=end).collect();
 
-        let line = lines
-            .next()
-            .await
-            .context(ItelexServerErrorKind::UserInputError)?
-            .context(ItelexServerErrorKind::ConnectionCloseUnexpected)?;
+                let line = String::from_utf8(line).context(ItelexServerErrorKind::UserInputError)?;
+
+                return Ok(String::from(line.trim_end_matches(['\r', '\n'])));
+            }
+
+            if self.ascii_buffer.len() > MAX_ASCII_LINE_LENGTH {
+                bail!(ItelexServerErrorKind::UserInputError);
+            }
+
+            let mut buffer = [0_u8; 256];
+
+            let len = self
+                .socket
+                .read(&mut buffer)
+                .await
+                .context(ItelexServerErrorKind::ConnectionCloseUnexpected)?;
+
+            if len == 0 {
+                if self.ascii_buffer.is_empty() {
+                    bail!(ItelexServerErrorKind::ConnectionCloseUnexpected);
+                }
+
+                // the client closed the connection without ending its last line
+                self.ascii_buffer.push(b'\n');
+
+                continue;
+            }
+
+            self.ascii_buffer.extend_from_slice(&buffer[..len]);
+        }
+    }
+
+    pub async fn consume_package_ascii(self: &mut Self) -> anyhow::Result<()> {
+        let line = self.read_line_ascii().await?;
 
         debug!("full line: {}", line);
 
         if line.is_empty() {
+            if self.ascii_session {
+                return Ok(());
+            }
+
             bail!(ItelexServerErrorKind::UserInputError);
         }
 
-        if line.chars().next().context(ItelexServerErrorKind::UserInputError)? == 'q' {
-            let mut number = String::new();
+        let message = match self.handle_line_ascii(&line) {
+            Ok(message) => message,
+            // NOTE: a session is not ended by a mistyped command
+            Err(err)
+                if self.ascii_session
+                    && err.downcast_ref() == Some(&ItelexServerErrorKind::UserInputError) =>
+            {
+                debug!("invalid ascii command: {:?}", line);
+
+                format!("fail\r\n{}\r\ninvalid command\r\n+++\r\n", line)
+            }
+            Err(err) => return Err(err),
+        };
 
-            for character in line.chars().skip(1) {
-                if !character.is_digit(10) {
-                    break; // number is over
+        self.socket
+            .write_all(message.as_bytes())
+            .await
+            .context(ItelexServerErrorKind::FailedToWrite)?;
+
+        if !self.ascii_session {
+            self.shutdown()?;
+        }
+
+        Ok(())
+    }
+
+    /// The reply to the ASCII command `line`
+    fn handle_line_ascii(&mut self, line: &str) -> anyhow::Result<String> {
+        let mut chars = line.chars();
+
+        let command = chars.next().context(ItelexServerErrorKind::UserInputError)?;
+
+        let argument = chars.as_str().trim();
+
+        let message = match command {
+            'q' => {
+                let mut number = String::new();
+
+                for character in line.chars().skip(1) {
+                    if !character.is_digit(10) {
+                        break; // number is over
+                    }
+
+                    number.push(character);
                 }
 
-                number.push(character);
-            }
+                debug!("handling 'q' request");
 
-            debug!("handling 'q' request");
+                let number = number
+                    .as_str()
+                    .parse::<u32>()
+                    .context(ItelexServerErrorKind::UserInputError)?;
 
-            let number =
-                number.as_str().parse::<u32>().context(ItelexServerErrorKind::UserInputError)?;
+                debug!("parsed number: '{}'", number);
 
-            debug!("parsed number: '{}'", number);
+                if let Some(entry) = get_public_entry_by_number(number)? {
+                    ascii::AsciiEntry::from_entry(&entry)?.to_string()
+                } else {
+                    ascii::not_found(number)
+                }
+            }
+            's' => {
+                debug!("handling 's' request for: '{}'", argument);
 
-            let message = if let Some(entry) = get_public_entry_by_number(number) {
-                let address = if let Some(hostname) = entry.hostname() {
-                    String::from(hostname)
+                if search::is_public_pattern(argument) {
+                    let entries = get_public_entries_by_pattern(argument)?;
+
+                    if entries.is_empty() {
+                        ascii::not_found(argument)
+                    } else {
+                        ascii::format_entries(&entries)?
+                    }
                 } else {
-                    let ipaddress = entry.ipaddress().context(
-                        "database is incosistent: entry has neither hostname nor ipaddress",
-                    )?;
+                    ascii::pattern_too_short(argument)
+                }
+            }
+            'c' => {
+                debug!("handling 'c' request");
 
-                    format!("{}", ipaddress)
+                let count = if argument.is_empty() {
+                    DEFAULT_ASCII_CHANGED_COUNT
+                } else {
+                    argument.parse::<usize>().context(ItelexServerErrorKind::UserInputError)?
                 };
 
-                format!(
-                    "ok\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n+++\r\n",
-                    entry.number,
-                    entry.name.0,
-                    entry.client_type,
-                    address,
-                    entry.port,
-                    entry
-                        .extension_as_str()
-                        .map_err(|ext| anyhow!(format!("ivalid extension: {}", ext)))?,
-                )
-            } else {
-                format!("fail\r\n{}\r\nunknown\r\n+++\r\n", number)
-            };
-
-            self.socket
-                .write_all(message.as_bytes())
-                .await
-                .context(ItelexServerErrorKind::FailedToWrite)?
-        } else {
-            bail!(ItelexServerErrorKind::UserInputError);
-        }
+                let mut entries = get_public_entries()?;
 
-        self.shutdown()?;
+                entries.sort_by_key(|entry| std::cmp::Reverse(entry.timestamp));
 
-        Ok(())
+                entries.truncate(count.min(MAX_ASCII_CHANGED_COUNT));
+
+                if entries.is_empty() {
+                    String::from("fail\r\nno entries\r\n+++\r\n")
+                } else {
+                    ascii::format_entries(&entries)?
+                }
+            }
+            'v' => format!(
+                "ok\r\n{} {}\r\n+++\r\n",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            ),
+            'h' => String::from(ASCII_HELP),
+            'm' => {
+                debug!("starting ascii session");
+
+                self.ascii_session = true;
+
+                String::from("ok\r\nsession\r\n+++\r\n")
+            }
+            'x' => {
+                self.ascii_session = false;
+
+                String::from("ok\r\nbye\r\n+++\r\n")
+            }
+            _ => bail!(ItelexServerErrorKind::UserInputError),
+        };
+
+        Ok(message)
     }
 
     pub async fn consume_package_binary(self: &mut Self) -> anyhow::Result<()> {
@@ -295,7 +431,9 @@ impl Client {
                     _ => bail!(ItelexServerErrorKind::Ipv6Address),
                 };
 
-                update_or_register_entry(*package, ipaddress)?;
+                // NOTE: writes wait for the disk, which must not block the runtime
+                task::spawn_blocking(move || update_or_register_entry(*package, ipaddress))
+                    .await??;
                 self.send_package(AddressConfirm { ipaddress }).await?;
 
                 Ok(())
@@ -309,7 +447,7 @@ impl Client {
                     bail!(ItelexServerErrorKind::InvalidState(State::Idle, self.state));
                 }
 
-                if let Some(entry) = get_public_entry_by_number(package.number) {
+                if let Some(entry) = get_public_entry_by_number(package.number)? {
                     self.send_package(entry).await?;
                 } else {
                     self.send_package(PeerNotFound {}).await?;
@@ -325,7 +463,22 @@ impl Client {
                     bail!(ItelexServerErrorKind::InvalidState(State::Accepting, self.state));
                 }
 
-                update_entry_if_newer(package);
+                // NOTE: an invalid entry is not a reason to abort the sync
+                if let Err(err) = validate_entry(&package) {
+                    warn!("Ignoring entry from {}: {}", self.address, err);
+                } else {
+                    let timestamp = package.timestamp;
+                    let too_far_ahead = quarantine::is_too_far_in_future(&package)
+                        || conflicts::exceeds_clock_skew(&package).is_some();
+
+                    let address = self.address;
+                    task::spawn_blocking(move || update_entry_from_peer(package, address)).await??;
+
+                    // held back timestamps would make the watermark useless
+                    if !too_far_ahead {
+                        replication::record_received(self.address, timestamp);
+                    }
+                }
 
                 self.send_package(Acknowledge {}).await?;
 
@@ -347,7 +500,7 @@ impl Client {
 
                 self.state = State::Responding;
 
-                self.send_queue.extend(get_all_entries());
+                self.send_queue.extend(get_all_entries()?);
 
                 self.send_queue_entry().await?;
 
@@ -405,11 +558,12 @@ impl Client {
                     bail!(ItelexServerErrorKind::InvalidState(State::Idle, self.state));
                 }
 
-                let entries = get_public_entries_by_pattern(&package.pattern);
+                let entries = get_public_entries_by_pattern(&package.pattern)?;
 
                 self.state = State::Responding;
 
-                self.send_queue.extend(entries);
+                // NOTE: the send queue is sent from the back, so the best match has to be last
+                self.send_queue.extend(entries.into_iter().rev());
 
                 self.send_queue_entry().await?;
 
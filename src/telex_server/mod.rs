@@ -1,5 +1,6 @@
 #[macro_use]
 pub mod errors;
+pub mod ascii;
 pub mod background_tasks;
 pub mod client;
 
@@ -51,6 +52,8 @@ pub fn init(stop_server: oneshot::Receiver<()>) -> ResultJoinHandle {
             }
             futures::future::join_all(background_task_handles).await;
 
+            background_tasks::stop_peer_tasks().await;
+
             Ok(())
         },
     )
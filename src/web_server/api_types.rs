@@ -1,8 +1,80 @@
+use crate::{db::transfer, telex_server::background_tasks::JobKind};
+
 #[derive(serde::Deserialize, Debug)]
 pub struct LoginRequest {
     pub password: String,
 }
 
+#[derive(serde::Deserialize, Debug)]
+pub struct ImportQuery {
+    pub format: transfer::TransferFormat,
+    #[serde(default = "default_import_mode")]
+    pub mode: transfer::ImportMode,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+const fn default_import_mode() -> transfer::ImportMode {
+    transfer::ImportMode::Merge
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct EntriesQuery {
+    /// Only list the entries whose names match this pattern
+    #[serde(default)]
+    pub search: Option<String>,
+    #[serde(default)]
+    pub client_type: Option<u8>,
+    #[serde(default)]
+    pub disabled: Option<bool>,
+}
+
+/// How lookups and searches are answered
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplyFormat {
+    Json,
+    /// Exactly what the ASCII protocol would reply
+    Text,
+}
+
+const fn default_reply_format() -> ReplyFormat {
+    ReplyFormat::Json
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct LookupQuery {
+    #[serde(default = "default_reply_format")]
+    pub format: ReplyFormat,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct SearchQuery {
+    pub q: String,
+    #[serde(default = "default_reply_format")]
+    pub format: ReplyFormat,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct PeerRequest {
+    /// `<hostname>:<port>` of the peer
+    pub host: String,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct PeerDisabledRequest {
+    pub host: String,
+    pub disabled: bool,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct JobRequest {
+    pub kind: JobKind,
+    /// The host of the peer to run the job for, instead of all of them
+    #[serde(default)]
+    pub peer: Option<String>,
+}
+
 #[derive(serde::Serialize, Debug)]
 pub struct LoggedInResponse(pub bool); // TODO: remove?
 
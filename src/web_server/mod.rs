@@ -78,8 +78,14 @@ macro_rules! static_route {
     };
 }
 
+fn web_source(req: &Request<()>) -> ChangeSource {
+    ChangeSource::web_session(req.session().id())
+}
+
 const INDEX_HTML: &str = static_file!("index.html");
 const ENTRY_HTML: &str = static_file!("entry.html");
+const CONFLICTS_HTML: &str = static_file!("conflicts.html");
+const PEERS_HTML: &str = static_file!("peers.html");
 const LOGIN_HTML: &str = static_file!("login.html");
 const MAIN_CSS: &str = static_file!("main.css");
 const API_JS: &str = static_file!("api.js");
@@ -116,6 +122,8 @@ pub fn init(stop_server: oneshot::Receiver<()>) -> ResultJoinHandle {
         let mut static_files = server.at("/static");
         static_route!(static_files, "/index.html", "text/html", INDEX_HTML);
         static_route!(static_files, "/entry.html", "text/html", ENTRY_HTML);
+        static_route!(static_files, "/conflicts.html", "text/html", CONFLICTS_HTML);
+        static_route!(static_files, "/peers.html", "text/html", PEERS_HTML);
         static_route!(static_files, "/login.html", "text/html", LOGIN_HTML);
         static_route!(static_files, "/api.js", "text/javascript", API_JS);
         static_route!(static_files, "/main.js", "text/javascript", MAIN_JS);
@@ -123,10 +131,31 @@ pub fn init(stop_server: oneshot::Receiver<()>) -> ResultJoinHandle {
 
         let mut api = server.at("/api");
         api.at("/entry/:number").get(api_get_entry_number);
+        api.at("/entry/:number/history").get(api_get_entry_number_history);
+        api.at("/entry/:number/revert/:time/:timestamp").post(api_revert_entry_number);
         api.at("/entry").post(api_post_entry);
         api.at("/entry/:number").post(api_post_entry_number);
         api.at("/reset_pin/:number").get(api_reset_pin_number);
         api.at("/entries").get(api_get_entries);
+        api.at("/lookup/:number").get(api_lookup);
+        api.at("/search").get(api_search);
+        api.at("/export.json").get(|req| api_export(req, transfer::TransferFormat::Json));
+        api.at("/export.csv").get(|req| api_export(req, transfer::TransferFormat::Csv));
+        api.at("/import").post(api_import);
+        api.at("/conflicts").get(api_get_conflicts);
+        api.at("/conflicts/:id/resolve/:winner").post(api_resolve_conflict);
+        api.at("/quarantine").get(api_get_quarantine);
+        api.at("/quarantine/:id/accept").post(api_accept_quarantined);
+        api.at("/quarantine/:id/discard").post(api_discard_quarantined);
+        api.at("/peers").get(api_get_peers);
+        api.at("/peers").post(api_add_peer);
+        api.at("/peers/remove").post(api_remove_peer);
+        api.at("/peers/disabled").post(api_set_peer_disabled);
+        api.at("/jobs").get(api_get_jobs);
+        api.at("/jobs").post(api_start_job);
+        api.at("/jobs/:id").get(api_get_job);
+        api.at("/snapshots").get(api_get_snapshots);
+        api.at("/snapshots/:id/restore").post(api_restore_snapshot);
         api.at("/logout").get(api_logout);
         api.at("/login").post(api_login);
         api.at("/logged-in").get(api_logged_in);
@@ -159,8 +188,49 @@ async fn api_get_entry_number(req: Request<()>) -> tide::Result {
     };
 
     match entry {
-        Some(entry) => res!(entry),
-        None => res!(Err("Not Found"), 404),
+        Ok(Some(entry)) => res!(entry),
+        Ok(None) => res!(Err("Not Found"), 404),
+        Err(err) => res!(Err(format!("Failed to get entry: {:?}", err))),
+    }
+}
+
+async fn api_get_entry_number_history(req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    let number = match req.param::<u32>("number") {
+        Ok(number) => number,
+        Err(_) => return res!(Err("failed to parse number")),
+    };
+
+    // NOTE: the history does not contain pins, which are never sent to the web interface
+    res!(history::get_history(number))
+}
+
+async fn api_revert_entry_number(req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    let number: u32 = match req.param("number") {
+        Ok(number) => number,
+        Err(_) => return res!(Err("failed to parse number")),
+    };
+
+    let time: u32 = match req.param("time") {
+        Ok(time) => time,
+        Err(_) => return res!(Err("failed to parse time")),
+    };
+
+    let timestamp: u32 = match req.param("timestamp") {
+        Ok(timestamp) => timestamp,
+        Err(_) => return res!(Err("failed to parse timestamp")),
+    };
+
+    match history::revert_entry(number, time, timestamp, web_source(&req)) {
+        Ok(()) => res!(Ok),
+        Err(err) => res!(Err(format!("Failed to revert entry: {:?}", err))),
     }
 }
 
@@ -185,24 +255,27 @@ async fn api_post_entry(mut req: Request<()>) -> tide::Result {
         Err(_) => return res!(Err("Failed to deserialize request")),
     };
 
-    {
-        // confirm entry format
-        if let Err(err) = entry.serialize(&mut Vec::new()) {
-            return res!(Err(format!("Entry has invalid format: {:?}", err)));
-        }
+    if let Err(err) = validate_entry(&entry) {
+        return res!(err, 400);
     }
 
-    if let Some(target) = DATABASE.get(&entry.number) {
-        if !(target.client_type == ClientType::Deleted || target.disabled()) {
-            return res!(Err("Refused to overwrite existing entry"));
+    match get_entry_by_number(entry.number) {
+        Ok(Some(target)) => {
+            if !(target.client_type == ClientType::Deleted || target.disabled()) {
+                return res!(Err("Refused to overwrite existing entry"));
+            }
         }
+        Ok(None) => {}
+        Err(err) => return res!(Err(format!("Failed to get entry: {:?}", err))),
     }
 
     let current_timestamp = get_current_itelex_timestamp();
     entry.timestamp = current_timestamp; // update the entry's timestamp
     entry.pin = 0; // do _not_ write user supplied pins
 
-    update_entry(entry);
+    if let Err(err) = update_entry(entry, web_source(&req)) {
+        return res!(Err(format!("Failed to update entry: {:?}", err)));
+    }
 
     res!(Ok)
 }
@@ -223,37 +296,46 @@ async fn api_post_entry_number(mut req: Request<()>) -> tide::Result {
         Err(_) => return res!(Err("Failed to deserialize request")),
     };
 
-    {
-        // confirm entry format
-        if let Err(err) = entry.serialize(&mut Vec::new()) {
-            return res!(Err(format!("Entry has invalid format: {:?}", err)));
-        }
+    if let Err(err) = validate_entry(&entry) {
+        return res!(err, 400);
     }
 
     if entry.number != number {
-        if let Some(target) = DATABASE.get(&entry.number) {
-            if !(target.client_type == ClientType::Deleted || target.disabled()) {
-                return res!(Err("Refused to overwrite existing target entry"));
+        match get_entry_by_number(entry.number) {
+            Ok(Some(target)) => {
+                if !(target.client_type == ClientType::Deleted || target.disabled()) {
+                    return res!(Err("Refused to overwrite existing target entry"));
+                }
             }
+            Ok(None) => {}
+            Err(err) => return res!(Err(format!("Failed to get entry: {:?}", err))),
         }
     }
 
     let current_timestamp = get_current_itelex_timestamp();
     entry.timestamp = current_timestamp; // update the entry's timestamp
-    entry.pin = if let Some(mut old_entry) = DATABASE.get_mut(&number) {
-        let mut old_entry: &mut UnboxedEntry = old_entry.value_mut();
-        if entry.number != number {
-            old_entry.client_type = ClientType::Deleted; // delete the old entry
-            old_entry.timestamp = current_timestamp; // set it's timestamp to `now`
-            CHANGED.insert(number, ()); // mark it as changed
-        }
 
-        old_entry.pin
-    } else {
-        return res!(Err("entry does not exist"));
-    }; // update the entry's pin
+    let mut old_entry = match get_entry_by_number(number) {
+        Ok(Some(old_entry)) => old_entry,
+        Ok(None) => return res!(Err("entry does not exist")),
+        Err(err) => return res!(Err(format!("Failed to get entry: {:?}", err))),
+    };
 
-    update_entry(entry); // overwrites old_entry if number == entry.number
+    entry.pin = old_entry.pin; // update the entry's pin
+
+    if entry.number != number {
+        old_entry.client_type = ClientType::Deleted; // delete the old entry
+        old_entry.timestamp = current_timestamp; // set it's timestamp to `now`
+
+        if let Err(err) = update_entry(old_entry, web_source(&req)) {
+            return res!(Err(format!("Failed to delete old entry: {:?}", err)));
+        }
+    }
+
+    // overwrites old_entry if number == entry.number
+    if let Err(err) = update_entry(entry, web_source(&req)) {
+        return res!(Err(format!("Failed to update entry: {:?}", err)));
+    }
 
     res!(Ok)
 }
@@ -268,19 +350,376 @@ async fn api_reset_pin_number(req: Request<()>) -> tide::Result {
         Err(_) => return res!(Err("failed to parse number")),
     };
 
-    if let Some(mut entry) = DATABASE.get_mut(&number) {
-        entry.pin = 0;
-    } else {
-        return res!(Err("entry does not exist"));
+    match reset_pin(number, web_source(&req)) {
+        Ok(true) => res!(Ok),
+        Ok(false) => res!(Err("entry does not exist")),
+        Err(err) => res!(Err(format!("Failed to reset pin: {:?}", err))),
     }
-
-    res!(Ok)
 }
 
 async fn api_get_entries(req: Request<()>) -> tide::Result {
+    let query: EntriesQuery = match req.query() {
+        Ok(query) => query,
+        Err(_) => return res!(Err("failed to parse query"), 400),
+    };
+
+    let filter = EntryFilter {
+        pattern: query.search.as_deref(),
+        client_type: query.client_type,
+        disabled: query.disabled,
+        public_only: !logged_in!(req),
+    };
+
+    match find_entries(&filter) {
+        Ok(entries) => res!(entries),
+        Err(err) => res!(Err(format!("Failed to get entries: {:?}", err))),
+    }
+}
+
+fn ascii_reply(reply: String, code: u16) -> tide::Result {
+    let mut body = tide::Body::from_string(reply);
+    body.set_mime("text/plain;charset=UTF-8");
+
+    res!(Raw(body, code))
+}
+
+async fn api_lookup(req: Request<()>) -> tide::Result {
+    let number: u32 = match req.param("number") {
+        Ok(number) => number,
+        Err(_) => return res!(Err("failed to parse number"), 400),
+    };
+
+    let query: LookupQuery = match req.query() {
+        Ok(query) => query,
+        Err(_) => return res!(Err("failed to parse query"), 400),
+    };
+
+    let entry = match get_public_entry_by_number(number) {
+        Ok(entry) => entry,
+        Err(err) => return res!(Err(format!("Failed to get entry: {:?}", err))),
+    };
+
+    let entry = match entry.map(|entry| ascii::AsciiEntry::from_entry(&entry)).transpose() {
+        Ok(entry) => entry,
+        Err(err) => return res!(Err(format!("Failed to format entry: {:?}", err))),
+    };
+
+    match (entry, query.format) {
+        (Some(entry), ReplyFormat::Json) => res!(entry),
+        (Some(entry), ReplyFormat::Text) => ascii_reply(entry.to_string(), 200),
+        (None, ReplyFormat::Json) => res!(Err("Not Found"), 404),
+        (None, ReplyFormat::Text) => ascii_reply(ascii::not_found(number), 404),
+    }
+}
+
+async fn api_search(req: Request<()>) -> tide::Result {
+    let query: SearchQuery = match req.query() {
+        Ok(query) => query,
+        Err(_) => return res!(Err("failed to parse query"), 400),
+    };
+
+    if !search::is_public_pattern(&query.q) {
+        return match query.format {
+            ReplyFormat::Json => res!(Err("pattern too short"), 400),
+            ReplyFormat::Text => ascii_reply(ascii::pattern_too_short(query.q.trim()), 400),
+        };
+    }
+
+    let entries = match get_public_entries_by_pattern(query.q.trim()) {
+        Ok(entries) => entries,
+        Err(err) => return res!(Err(format!("Failed to search entries: {:?}", err))),
+    };
+
+    match query.format {
+        ReplyFormat::Json => {
+            let entries: anyhow::Result<Vec<ascii::AsciiEntry>> =
+                entries.iter().map(ascii::AsciiEntry::from_entry).collect();
+
+            match entries {
+                Ok(entries) => res!(entries),
+                Err(err) => res!(Err(format!("Failed to format entries: {:?}", err))),
+            }
+        }
+        ReplyFormat::Text => {
+            if entries.is_empty() {
+                return ascii_reply(ascii::not_found(query.q.trim()), 200);
+            }
+
+            match ascii::format_entries(&entries) {
+                Ok(reply) => ascii_reply(reply, 200),
+                Err(err) => res!(Err(format!("Failed to format entries: {:?}", err))),
+            }
+        }
+    }
+}
+
+async fn api_export(req: Request<()>, format: transfer::TransferFormat) -> tide::Result {
     let result = if logged_in!(req) { get_sanitized_entries() } else { get_public_entries() };
 
-    res!(result)
+    match result.and_then(|entries| transfer::export_entries(format, &entries)) {
+        Ok(export) => {
+            let mut body = tide::Body::from_string(export);
+            body.set_mime(match format {
+                transfer::TransferFormat::Json => "application/json;charset=UTF-8",
+                transfer::TransferFormat::Csv => "text/csv;charset=UTF-8",
+            });
+
+            res!(Raw(body))
+        }
+        Err(err) => res!(Err(format!("Failed to export entries: {:?}", err))),
+    }
+}
+
+async fn api_import(mut req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    let query: ImportQuery = match req.query() {
+        Ok(query) => query,
+        Err(_) => return res!(Err("failed to parse query")),
+    };
+
+    let data = match req.body_string().await {
+        Ok(data) => data,
+        Err(_) => return res!(Err("Failed to read request")),
+    };
+
+    let source = web_source(&req);
+
+    match transfer::import_entries(query.format, query.mode, query.dry_run, &data, source) {
+        Ok(report) => res!(report),
+        Err(err) => res!(Err(format!("Failed to import entries: {:?}", err))),
+    }
+}
+
+async fn api_get_conflicts(req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    let mut conflicts = conflicts::get_unresolved_conflicts();
+
+    // NOTE: pins are never sent to the web interface
+    for conflict in conflicts.iter_mut() {
+        if let Some(local) = conflict.local.as_mut() {
+            local.pin = 0;
+        }
+
+        conflict.remote.pin = 0;
+    }
+
+    res!(conflicts)
+}
+
+async fn api_resolve_conflict(req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    let id: u64 = match req.param("id") {
+        Ok(id) => id,
+        Err(_) => return res!(Err("failed to parse conflict id")),
+    };
+
+    let winner: conflicts::Winner = match req.param("winner") {
+        Ok(winner) => winner,
+        Err(_) => return res!(Err("failed to parse winner")),
+    };
+
+    match conflicts::resolve_conflict(id, winner, web_source(&req)) {
+        Ok(()) => res!(Ok),
+        Err(err) => res!(Err(format!("Failed to resolve conflict: {:?}", err))),
+    }
+}
+
+async fn api_get_quarantine(req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    let mut quarantined = quarantine::get_quarantined_entries();
+
+    // NOTE: pins are never sent to the web interface
+    for quarantined in quarantined.iter_mut() {
+        quarantined.entry.pin = 0;
+    }
+
+    res!(quarantined)
+}
+
+async fn api_accept_quarantined(req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    let id: u64 = match req.param("id") {
+        Ok(id) => id,
+        Err(_) => return res!(Err("failed to parse id")),
+    };
+
+    match quarantine::accept_quarantined_entry(id, web_source(&req)) {
+        Ok(()) => res!(Ok),
+        Err(err) => res!(Err(format!("Failed to accept entry: {:?}", err))),
+    }
+}
+
+async fn api_discard_quarantined(req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    let id: u64 = match req.param("id") {
+        Ok(id) => id,
+        Err(_) => return res!(Err("failed to parse id")),
+    };
+
+    match quarantine::discard_quarantined_entry(id) {
+        Ok(()) => res!(Ok),
+        Err(err) => res!(Err(format!("Failed to discard entry: {:?}", err))),
+    }
+}
+
+async fn api_get_peers(req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    res!(peers::peer_statuses())
+}
+
+async fn api_add_peer(mut req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    let body: PeerRequest = match req.body_json().await {
+        Ok(body) => body,
+        Err(_) => return res!(Err("Failed to deserialize request")),
+    };
+
+    let host = body.host.trim();
+
+    let address = match config::resolve_server(host).await {
+        Ok(Some(address)) => address,
+        Ok(None) => return res!(Err("peer has no address"), 400),
+        Err(err) => return res!(Err(format!("Failed to resolve peer: {:?}", err)), 400),
+    };
+
+    if let Err(err) = peers::add_peer(host.to_owned(), address) {
+        return res!(Err(format!("Failed to add peer: {:?}", err)));
+    }
+
+    background_tasks::peers_changed();
+
+    res!(Ok)
+}
+
+async fn api_remove_peer(mut req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    let body: PeerRequest = match req.body_json().await {
+        Ok(body) => body,
+        Err(_) => return res!(Err("Failed to deserialize request")),
+    };
+
+    if let Err(err) = peers::remove_peer(&body.host) {
+        return res!(Err(format!("Failed to remove peer: {:?}", err)));
+    }
+
+    background_tasks::peers_changed();
+
+    res!(Ok)
+}
+
+async fn api_set_peer_disabled(mut req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    let body: PeerDisabledRequest = match req.body_json().await {
+        Ok(body) => body,
+        Err(_) => return res!(Err("Failed to deserialize request")),
+    };
+
+    if let Err(err) = peers::set_peer_disabled(&body.host, body.disabled) {
+        return res!(Err(format!("Failed to update peer: {:?}", err)));
+    }
+
+    background_tasks::peers_changed();
+
+    res!(Ok)
+}
+
+async fn api_get_jobs(req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    res!(background_tasks::get_jobs())
+}
+
+async fn api_start_job(mut req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    let body: JobRequest = match req.body_json().await {
+        Ok(body) => body,
+        Err(_) => return res!(Err("Failed to deserialize request")),
+    };
+
+    match background_tasks::start_job(body.kind, body.peer) {
+        Ok(id) => res!(id),
+        Err(err) => res!(Err(format!("Failed to start job: {:?}", err)), 400),
+    }
+}
+
+async fn api_get_job(req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    let id: u64 = match req.param("id") {
+        Ok(id) => id,
+        Err(_) => return res!(Err("failed to parse job id")),
+    };
+
+    let job = match background_tasks::get_job(id) {
+        Some(job) => job,
+        None => return res!(Err("there is no job with this id"), 404),
+    };
+
+    res!(job)
+}
+
+async fn api_get_snapshots(req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    match snapshots::list_snapshots() {
+        Ok(snapshots) => res!(snapshots),
+        Err(err) => res!(Err(format!("Failed to list snapshots: {:?}", err))),
+    }
+}
+
+async fn api_restore_snapshot(req: Request<()>) -> tide::Result {
+    if !logged_in!(req) {
+        return res!(Err("Not logged in"));
+    }
+
+    let id: u64 = match req.param("id") {
+        Ok(id) => id,
+        Err(_) => return res!(Err("failed to parse snapshot id")),
+    };
+
+    match snapshots::restore_snapshot(id) {
+        Ok(n_restored) => res!(n_restored),
+        Err(err) => res!(Err(format!("Failed to restore snapshot: {:?}", err))),
+    }
 }
 
 async fn api_logout(mut req: Request<()>) -> tide::Result {
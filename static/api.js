@@ -12,6 +12,12 @@ function api_call(method, endpoint, callback, data) {
           "API call: " + method + " to " + endpoint + " failed: " + xhr.response
         );
 
+        if (err && Array.isArray(err.errors)) {
+          err = err.errors
+            .map(error => error.field + ": " + error.message)
+            .join("\n");
+        }
+
         if (err) {
           alert("Server Error: " + err);
           throw err;
@@ -38,8 +44,90 @@ function get_entry(number, callback) {
   api_call("GET", "entry/" + number, res => callback(inferDeletedField(res)));
 }
 
-function get_entries(callback) {
-  api_call("GET", "entries", res => callback(res.map(inferDeletedField)));
+function get_history(number, callback) {
+  api_call("GET", "entry/" + number + "/history", res =>
+    callback(
+      res.map(record => {
+        if (record.old) inferDeletedField(record.old);
+        inferDeletedField(record.new);
+        return record;
+      })
+    )
+  );
+}
+
+function revert_entry(number, record, callback) {
+  api_call(
+    "POST",
+    "entry/" + number + "/revert/" + record.time + "/" + record.new.timestamp,
+    callback
+  );
+}
+
+function get_conflicts(callback) {
+  api_call("GET", "conflicts", res =>
+    callback(
+      res.map(conflict => {
+        if (conflict.local) inferDeletedField(conflict.local);
+        inferDeletedField(conflict.remote);
+        return conflict;
+      })
+    )
+  );
+}
+
+function resolve_conflict(id, winner, callback) {
+  api_call("POST", "conflicts/" + id + "/resolve/" + winner, callback);
+}
+
+function get_quarantine(callback) {
+  api_call("GET", "quarantine", res =>
+    callback(
+      res.map(quarantined => {
+        inferDeletedField(quarantined.entry);
+        return quarantined;
+      })
+    )
+  );
+}
+
+function accept_quarantined(id, callback) {
+  api_call("POST", "quarantine/" + id + "/accept", callback);
+}
+
+function discard_quarantined(id, callback) {
+  api_call("POST", "quarantine/" + id + "/discard", callback);
+}
+
+function get_peers(callback) {
+  api_call("GET", "peers", callback);
+}
+
+function add_peer(host, callback) {
+  api_call("POST", "peers", callback, { host });
+}
+
+function remove_peer(host, callback) {
+  api_call("POST", "peers/remove", callback, { host });
+}
+
+function set_peer_disabled(host, disabled, callback) {
+  api_call("POST", "peers/disabled", callback, { host, disabled });
+}
+
+function start_job(kind, peer, callback) {
+  api_call("POST", "jobs", callback, { kind, peer });
+}
+
+function get_job(id, callback) {
+  api_call("GET", "jobs/" + id, callback);
+}
+
+function get_entries(callback, search) {
+  let endpoint = "entries";
+  if (search) endpoint += "?search=" + encodeURIComponent(search);
+
+  api_call("GET", endpoint, res => callback(res.map(inferDeletedField)));
 }
 
 function login(password, callback) {
@@ -56,6 +56,33 @@ function formatValue(key, value) {
   return value;
 }
 
+function formatChanges(old_entry, new_entry) {
+  if (!old_entry) return locs.created;
+
+  let keys = [
+    "number",
+    "name",
+    "extension",
+    "ipaddress",
+    "hostname",
+    "client_type",
+    "port",
+    "disabled"
+  ];
+
+  return keys
+    .filter(key => old_entry[key] !== new_entry[key])
+    .map(
+      key =>
+        locs.labels[key] +
+        ": " +
+        formatValue(key, old_entry[key]) +
+        " → " +
+        formatValue(key, new_entry[key])
+    )
+    .join("\n");
+}
+
 function stringifyExtension(ext) {
   if (ext === 0) return "-";
   if (ext >= 1 && ext <= 99) return ext.toString().padStart(2, "0");